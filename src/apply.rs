@@ -1,117 +1,284 @@
 use crate::ast::Ast;
 use crate::instruction::Instruction;
+use crate::parser::classify_atom;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 
-pub fn apply_instruction(ast: Ast, instruction: Instruction) -> Ast {
-    match instruction {
-        Instruction::Insert { path, index, node } => apply_insert(ast, &path, index, node),
-        Instruction::Delete { path, index } => apply_delete(ast, &path, index),
-        Instruction::Update { path, new_value } => apply_update(ast, &path, new_value),
-        Instruction::Replace { path, node } => apply_replace(ast, &path, node),
-    }
+/// Why an instruction could not be applied to an AST.
+///
+/// `path` is the location of the offending node, which may be a prefix of the
+/// instruction's own path.
+#[derive(Debug, Clone)]
+pub enum ApplyError {
+    IndexOutOfBounds {
+        path: Vec<usize>,
+        index: usize,
+        len: usize,
+        instruction: Box<Instruction>,
+    },
+    ExpectedList {
+        path: Vec<usize>,
+        instruction: Box<Instruction>,
+    },
+    ExpectedAtom {
+        path: Vec<usize>,
+        instruction: Box<Instruction>,
+    },
 }
 
-fn apply_insert(ast: Ast, path: &[usize], index: usize, node: Ast) -> Ast {
-    if path.is_empty() {
-        if let Ast::List(mut list) = ast {
-            list.insert(index, node);
-            Ast::List(list)
-        } else {
-            panic!("Expected list at path");
-        }
-    } else {
-        let next_index = path[0];
-        if let Ast::List(list) = ast {
-            let mut new_list = list.clone();
-            let child = new_list[next_index].clone();
-            let new_child = apply_insert(child, &path[1..], index, node);
-            new_list[next_index] = new_child;
-            Ast::List(new_list)
-        } else {
-            panic!("Expected list at path");
+impl Display for ApplyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyError::IndexOutOfBounds {
+                path,
+                index,
+                len,
+                instruction,
+            } => write!(
+                f,
+                "Index {} out of bounds for list of length {} at path {:?} ({})",
+                index, len, path, instruction
+            ),
+            ApplyError::ExpectedList { path, instruction } => {
+                write!(f, "Expected list at path {:?} ({})", path, instruction)
+            }
+            ApplyError::ExpectedAtom { path, instruction } => {
+                write!(f, "Expected atom at path {:?} ({})", path, instruction)
+            }
         }
     }
 }
 
-fn apply_delete(ast: Ast, path: &[usize], index: usize) -> Ast {
-    if path.is_empty() {
-        if let Ast::List(mut list) = ast {
-            if index < list.len() {
-                list.remove(index);
-                Ast::List(list)
-            } else {
-                panic!("Index out of bounds");
-            }
-        } else {
-            panic!("Expected list at path");
-        }
-    } else {
-        let next_index = path[0];
-        if let Ast::List(list) = ast {
-            let mut new_list = list.clone();
-            let child = new_list[next_index].clone();
-            let new_child = apply_delete(child, &path[1..], index);
-            new_list[next_index] = new_child;
-            Ast::List(new_list)
-        } else {
-            panic!("Expected list at path");
-        }
+impl Error for ApplyError {}
+
+pub fn apply_instruction(ast: Ast, instruction: Instruction) -> Result<Ast, ApplyError> {
+    let mut ast = ast;
+    apply_instruction_mut(&mut ast, &instruction)?;
+    Ok(ast)
+}
+
+/// Applies `instructions` in order, stopping at the first failure. The error
+/// carries the index of the failing instruction; earlier instructions stay
+/// applied.
+pub fn apply_all(ast: &mut Ast, instructions: &[Instruction]) -> Result<(), (usize, ApplyError)> {
+    for (i, instruction) in instructions.iter().enumerate() {
+        apply_instruction_mut(ast, instruction).map_err(|err| (i, err))?;
     }
+    Ok(())
 }
 
-fn apply_update(ast: Ast, path: &[usize], new_value: String) -> Ast {
-    if path.is_empty() {
-        // Handle root-level atom update
-        match ast {
-            Ast::Atom(_) => Ast::Atom(new_value),
-            _ => panic!("Expected atom when updating with empty path"),
+/// `instructions` with the content each one removes or overwrites recorded,
+/// as found by applying them to `before` in order; see
+/// `Instruction::with_prior`.
+pub fn enrich(
+    before: &Ast,
+    instructions: &[Instruction],
+) -> Result<Vec<Instruction>, (usize, ApplyError)> {
+    let mut ast = before.clone();
+    let enrich_one = |(i, instruction): (usize, &Instruction)| {
+        let enriched = instruction.with_prior(&ast);
+        apply_instruction_mut(&mut ast, instruction).map_err(|err| (i, err))?;
+        Ok(enriched)
+    };
+    instructions.iter().enumerate().map(enrich_one).collect()
+}
+
+/// Applies `instruction` in place. On error `ast` is left unchanged.
+pub fn apply_instruction_mut(ast: &mut Ast, instruction: &Instruction) -> Result<(), ApplyError> {
+    match instruction {
+        Instruction::Insert { path, index, node } => {
+            let list = list_at(ast, path, instruction)?;
+            check_index(list, *index, list.len() + 1, path, instruction)?;
+            list.insert(*index, node.clone());
         }
-    } else if path.len() == 1 {
-        // Direct child of the root
-        let index = path[0];
-        if let Ast::List(mut list) = ast {
-            if index < list.len() {
-                if let Ast::Atom(_) = list[index] {
-                    list[index] = Ast::Atom(new_value);
-                    Ast::List(list)
-                } else {
-                    panic!("Expected atom at path");
+        Instruction::Delete { path, index, .. } => {
+            let list = list_at(ast, path, instruction)?;
+            check_index(list, *index, list.len(), path, instruction)?;
+            list.remove(*index);
+        }
+        Instruction::Update {
+            path, new_value, ..
+        } => match node_at(ast, path, instruction)? {
+            Ast::Str(value) => *value = new_value.clone(),
+            // The text decides whether a bare token is a symbol or a literal.
+            node @ (Ast::Atom(_) | Ast::Literal(..)) => *node = classify_atom(new_value),
+            _ => {
+                return Err(ApplyError::ExpectedAtom {
+                    path: path.clone(),
+                    instruction: Box::new(instruction.clone()),
+                });
+            }
+        },
+        Instruction::Replace { path, node, .. } => *node_at(ast, path, instruction)? = node.clone(),
+        Instruction::Move {
+            from_path,
+            from_index,
+            to_path,
+            to_index,
+        } => {
+            let list = list_at(ast, from_path, instruction)?;
+            check_index(list, *from_index, list.len(), from_path, instruction)?;
+            let node = list.remove(*from_index);
+            let inserted = list_at(ast, to_path, instruction).and_then(|list| {
+                check_index(list, *to_index, list.len() + 1, to_path, instruction)?;
+                Ok(list)
+            });
+            match inserted {
+                Ok(list) => list.insert(*to_index, node),
+                Err(err) => {
+                    // The source was valid a moment ago, so putting the node back cannot fail.
+                    list_at(ast, from_path, instruction)?.insert(*from_index, node);
+                    return Err(err);
                 }
-            } else {
-                panic!("Index out of bounds");
             }
-        } else {
-            panic!("Expected list at root");
         }
+    }
+    Ok(())
+}
+
+fn check_index(
+    list: &[Ast],
+    index: usize,
+    limit: usize,
+    path: &[usize],
+    instruction: &Instruction,
+) -> Result<(), ApplyError> {
+    if index < limit {
+        Ok(())
     } else {
-        // Handle nested paths
-        let first_index = path[0];
-        let rest_of_path = &path[1..];
-
-        if let Ast::List(mut list) = ast {
-            if first_index < list.len() {
-                list[first_index] =
-                    apply_update(list[first_index].clone(), rest_of_path, new_value);
-                Ast::List(list)
-            } else {
-                panic!("Index out of bounds at path segment");
+        Err(ApplyError::IndexOutOfBounds {
+            path: path.to_vec(),
+            index,
+            len: list.len(),
+            instruction: Box::new(instruction.clone()),
+        })
+    }
+}
+
+fn list_at<'a>(
+    ast: &'a mut Ast,
+    path: &[usize],
+    instruction: &Instruction,
+) -> Result<&'a mut Vec<Ast>, ApplyError> {
+    match node_at(ast, path, instruction)? {
+        Ast::List(_, list) => Ok(list),
+        _ => Err(ApplyError::ExpectedList {
+            path: path.to_vec(),
+            instruction: Box::new(instruction.clone()),
+        }),
+    }
+}
+
+fn node_at<'a>(
+    ast: &'a mut Ast,
+    path: &[usize],
+    instruction: &Instruction,
+) -> Result<&'a mut Ast, ApplyError> {
+    let mut current = ast;
+    for (depth, &index) in path.iter().enumerate() {
+        current = match current {
+            Ast::List(..) | Ast::Quoted(..) => {
+                let children = current.children_mut();
+                let len = children.len();
+                children
+                    .get_mut(index)
+                    .ok_or_else(|| ApplyError::IndexOutOfBounds {
+                        path: path[..depth].to_vec(),
+                        index,
+                        len,
+                        instruction: Box::new(instruction.clone()),
+                    })?
             }
-        } else {
-            panic!("Expected list at path segment");
-        }
+            _ => {
+                return Err(ApplyError::ExpectedList {
+                    path: path[..depth].to_vec(),
+                    instruction: Box::new(instruction.clone()),
+                });
+            }
+        };
     }
+    Ok(current)
 }
 
-fn apply_replace(ast: Ast, path: &[usize], node: Ast) -> Ast {
-    if path.is_empty() {
-        node
-    } else {
-        let next_index = path[0];
-        if let Ast::List(list) = ast {
-            let mut new_list = list.clone();
-            new_list[next_index] = apply_replace(new_list[next_index].clone(), &path[1..], node);
-            Ast::List(new_list)
-        } else {
-            panic!("Expected list at path");
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Ast::Atom;
+
+    fn sample() -> Ast {
+        Ast::list(vec![Ast::list(vec![
+            Atom("define".to_string()),
+            Atom("x".to_string()),
+            Atom("10".to_string()),
+        ])])
+    }
+
+    #[test]
+    fn test_apply_insert_out_of_bounds_path() {
+        let instruction = Instruction::Insert {
+            path: vec![3, 0],
+            index: 0,
+            node: Atom("y".to_string()),
+        };
+        let err = apply_instruction(sample(), instruction).unwrap_err();
+        assert!(matches!(
+            err,
+            ApplyError::IndexOutOfBounds { ref path, index: 3, len: 1, .. } if path.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_apply_update_on_list() {
+        let instruction = Instruction::Update {
+            path: vec![0],
+            new_value: "y".to_string(),
+            old_value: None,
+        };
+        let err = apply_instruction(sample(), instruction).unwrap_err();
+        assert!(matches!(err, ApplyError::ExpectedAtom { ref path, .. } if path == &vec![0]));
+    }
+
+    #[test]
+    fn test_apply_delete_through_atom() {
+        let instruction = Instruction::Delete {
+            path: vec![0, 1],
+            index: 0,
+            node: None,
+        };
+        let err = apply_instruction(sample(), instruction).unwrap_err();
+        assert!(matches!(err, ApplyError::ExpectedList { ref path, .. } if path == &vec![0, 1]));
+    }
+
+    #[test]
+    fn test_apply_all_reports_failing_index() {
+        let mut ast = sample();
+        let instructions = vec![
+            Instruction::Update {
+                path: vec![0, 1],
+                new_value: "y".to_string(),
+                old_value: None,
+            },
+            Instruction::Delete {
+                path: vec![0],
+                index: 7,
+                node: None,
+            },
+        ];
+        let (index, _) = apply_all(&mut ast, &instructions).unwrap_err();
+        assert_eq!(index, 1);
+        assert_eq!(ast.to_string(), "((define y 10))");
+    }
+
+    #[test]
+    fn test_apply_move_failure_leaves_ast_unchanged() {
+        let mut ast = sample();
+        let instruction = Instruction::Move {
+            from_path: vec![0],
+            from_index: 1,
+            to_path: vec![0],
+            to_index: 5,
+        };
+        assert!(apply_instruction_mut(&mut ast, &instruction).is_err());
+        assert_eq!(ast, sample());
     }
 }
@@ -1,26 +1,316 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::Display;
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Ast {
+    /// A symbol.
     Atom(String),
-    List(Vec<Ast>),
+    /// A self-evaluating atom, holding its source text.
+    Literal(LiteralKind, String),
+    /// A double-quoted string literal, holding the unescaped text.
+    Str(String),
+    /// Forms between a pair of delimiters. Parenthesized lists serialize as
+    /// a bare array, as they did before lists had delimiters.
+    #[serde(
+        serialize_with = "serialize_list",
+        deserialize_with = "deserialize_list"
+    )]
+    List(Delimiter, Vec<Ast>),
+    /// `'x`, `` `x ``, `,x`, `,@x` or `#'x`: the form `(quote x)` and friends,
+    /// remembering that it was written with the reader shorthand. Paths reach
+    /// the quoted form as child 0.
+    Quoted(ReaderMacro, Box<Ast>),
+    /// A comment or blank line, kept as a sibling of the forms around it.
+    Trivia(Trivia),
+    /// Source text the parser could not read, kept verbatim.
+    Error(String),
+    /// Where the parser expected something that was not there, such as the
+    /// closing delimiter of an unclosed list; holds what was expected and
+    /// prints as nothing.
+    Missing(String),
+}
+
+/// What kind of value a `Ast::Literal` denotes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LiteralKind {
+    /// `42`, `-7`
+    Int,
+    /// `3.14`, `1e-3`
+    Float,
+    /// `#t`, `#f`, `true`, `false`
+    Bool,
+    /// `#\a`, `#\space`
+    Char,
+    /// `:name`
+    Keyword,
+}
+
+impl LiteralKind {
+    pub fn is_numeric(self) -> bool {
+        matches!(self, LiteralKind::Int | LiteralKind::Float)
+    }
+}
+
+impl Display for LiteralKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LiteralKind::Int | LiteralKind::Float => "numeric literal",
+            LiteralKind::Bool => "boolean",
+            LiteralKind::Char => "character literal",
+            LiteralKind::Keyword => "keyword",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The bracket pair around a list.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Delimiter {
+    /// `( )`
+    #[default]
+    Paren,
+    /// `[ ]`
+    Square,
+    /// `{ }`
+    Curly,
+}
+
+impl Delimiter {
+    pub const ALL: [Delimiter; 3] = [Delimiter::Paren, Delimiter::Square, Delimiter::Curly];
+
+    pub fn open(self) -> &'static str {
+        match self {
+            Delimiter::Paren => "(",
+            Delimiter::Square => "[",
+            Delimiter::Curly => "{",
+        }
+    }
+
+    pub fn close(self) -> &'static str {
+        match self {
+            Delimiter::Paren => ")",
+            Delimiter::Square => "]",
+            Delimiter::Curly => "}",
+        }
+    }
+
+    /// What the closing delimiter is called in error messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            Delimiter::Paren => "parenthesis",
+            Delimiter::Square => "bracket",
+            Delimiter::Curly => "brace",
+        }
+    }
+
+    pub fn from_open(token: &str) -> Option<Self> {
+        Delimiter::ALL
+            .into_iter()
+            .find(|delimiter| delimiter.open() == token)
+    }
+
+    pub fn from_close(token: &str) -> Option<Self> {
+        Delimiter::ALL
+            .into_iter()
+            .find(|delimiter| delimiter.close() == token)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ListRepr<T> {
+    Paren(T),
+    Delimited { delimiter: Delimiter, items: T },
+}
+
+fn serialize_list<S: Serializer>(
+    delimiter: &Delimiter,
+    items: &Vec<Ast>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match delimiter {
+        Delimiter::Paren => ListRepr::Paren(items),
+        &delimiter => ListRepr::Delimited { delimiter, items },
+    }
+    .serialize(serializer)
+}
+
+fn deserialize_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<(Delimiter, Vec<Ast>), D::Error> {
+    Ok(match ListRepr::deserialize(deserializer)? {
+        ListRepr::Paren(items) => (Delimiter::Paren, items),
+        ListRepr::Delimited { delimiter, items } => (delimiter, items),
+    })
+}
+
+/// Reader shorthand for a two-element form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ReaderMacro {
+    /// `'x` for `(quote x)`
+    Quote,
+    /// `` `x `` for `(quasiquote x)`
+    Quasiquote,
+    /// `,x` for `(unquote x)`
+    Unquote,
+    /// `,@x` for `(unquote-splicing x)`
+    UnquoteSplicing,
+    /// `#'x` for `(function x)`
+    Function,
+}
+
+impl ReaderMacro {
+    pub const ALL: [ReaderMacro; 5] = [
+        ReaderMacro::Quote,
+        ReaderMacro::Quasiquote,
+        ReaderMacro::Unquote,
+        ReaderMacro::UnquoteSplicing,
+        ReaderMacro::Function,
+    ];
+
+    /// The characters written before the form.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ReaderMacro::Quote => "'",
+            ReaderMacro::Quasiquote => "`",
+            ReaderMacro::Unquote => ",",
+            ReaderMacro::UnquoteSplicing => ",@",
+            ReaderMacro::Function => "#'",
+        }
+    }
+
+    /// The symbol the shorthand stands for.
+    pub fn name(self) -> &'static str {
+        match self {
+            ReaderMacro::Quote => "quote",
+            ReaderMacro::Quasiquote => "quasiquote",
+            ReaderMacro::Unquote => "unquote",
+            ReaderMacro::UnquoteSplicing => "unquote-splicing",
+            ReaderMacro::Function => "function",
+        }
+    }
+
+    pub fn from_prefix(prefix: &str) -> Option<Self> {
+        ReaderMacro::ALL
+            .into_iter()
+            .find(|reader_macro| reader_macro.prefix() == prefix)
+    }
+}
+
+/// Source text that carries no meaning but is versioned with the code.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Trivia {
+    /// `;` up to the end of the line; holds the text after the `;`.
+    LineComment(String),
+    /// `#| ... |#`; holds the text between the delimiters.
+    BlockComment(String),
+    /// One or more empty lines between two forms.
+    BlankLine,
+}
+
+impl Ast {
+    /// A parenthesized list.
+    pub fn list(items: Vec<Ast>) -> Ast {
+        Ast::List(Delimiter::Paren, items)
+    }
+
+    /// The node at `path`, if there is one.
+    pub fn get(&self, path: &[usize]) -> Option<&Ast> {
+        path.iter()
+            .try_fold(self, |node, &index| node.children().get(index))
+    }
+
+    /// The nodes a path can step into: a list's items, or the form under a
+    /// reader macro.
+    pub fn children(&self) -> &[Ast] {
+        match self {
+            Ast::List(_, children) => children,
+            Ast::Quoted(_, form) => std::slice::from_ref(form),
+            _ => &[],
+        }
+    }
+
+    pub fn children_mut(&mut self) -> &mut [Ast] {
+        match self {
+            Ast::List(_, children) => children,
+            Ast::Quoted(_, form) => std::slice::from_mut(form),
+            _ => &mut [],
+        }
+    }
+
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Ast::Trivia(_))
+    }
+
+    /// Whether the node printed after this one has to start on a new line.
+    pub(crate) fn ends_line(&self) -> bool {
+        matches!(
+            self,
+            Ast::Trivia(Trivia::LineComment(_)) | Ast::Trivia(Trivia::BlankLine)
+        )
+    }
+}
+
+impl Display for Trivia {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trivia::LineComment(text) => write!(f, ";{}", text),
+            Trivia::BlockComment(text) => write!(f, "#|{}|#", text),
+            Trivia::BlankLine => Ok(()),
+        }
+    }
 }
 
 impl Display for Ast {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Ast::Atom(s) => write!(f, "{}", s),
-            Ast::List(v) => {
-                write!(f, "(")?;
+            Ast::Atom(s) | Ast::Literal(_, s) => write!(f, "{}", s),
+            Ast::Str(s) => write!(f, "\"{}\"", escape_string(s)),
+            Ast::List(delimiter, v) => {
+                write!(f, "{}", delimiter.open())?;
                 for (i, item) in v.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, " ")?;
+                    if i > 0 && !matches!(item, Ast::Missing(_)) {
+                        // Blank lines print as an empty line between their neighbours.
+                        if v[i - 1].ends_line() || *item == Ast::Trivia(Trivia::BlankLine) {
+                            writeln!(f)?;
+                        } else {
+                            write!(f, " ")?;
+                        }
                     }
                     write!(f, "{}", item)?;
                 }
-                write!(f, ")")
+                if v.last().is_some_and(Ast::ends_line) {
+                    writeln!(f)?;
+                }
+                if matches!(v.last(), Some(Ast::Missing(_))) {
+                    // The list was never closed.
+                    return Ok(());
+                }
+                write!(f, "{}", delimiter.close())
             }
+            Ast::Quoted(reader_macro, form) => write!(f, "{}{}", reader_macro.prefix(), form),
+            Ast::Trivia(trivia) => write!(f, "{}", trivia),
+            Ast::Error(text) => write!(f, "{}", text),
+            Ast::Missing(_) => Ok(()),
+        }
+    }
+}
+
+/// Escapes `text` for use between double quotes: backslashes, quotes and
+/// control characters become escape sequences that the parser reads back.
+pub fn escape_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
         }
     }
+    escaped
 }
@@ -0,0 +1,193 @@
+use crate::vc::Signature;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Settings from an INI-style config file, keyed `section.name`:
+///
+/// ```text
+/// [user]
+///     name = Ada Lovelace
+///     email = ada@example.com
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    values: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut values = BTreeMap::new();
+        let mut section = String::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_string();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!(
+                    "Config line {} is not 'key = value': {}",
+                    number + 1,
+                    line
+                ));
+            };
+            let key = key.trim();
+            let key = if section.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", section, key)
+            };
+            values.insert(key, value.trim().to_string());
+        }
+        Ok(Config { values })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        Ok(Config::parse(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// The user config overlaid with the repository's own `.trefoil/config`.
+    pub fn load_all(vcdir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut config = match user_config_path() {
+            Some(path) => Config::load(&path)?,
+            None => Config::default(),
+        };
+        config
+            .values
+            .extend(Config::load(&repository_config_path(vcdir))?.values);
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// The `(name, value)` pairs set in `section`.
+    pub fn section<'a>(&'a self, section: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.values.iter().filter_map(move |(key, value)| {
+            let (current, name) = key.split_once('.')?;
+            (current == section).then_some((name, value.as_str()))
+        })
+    }
+
+    /// Sets `section.name` to `value`. Keys must have a section.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key.split_once('.') {
+            Some((section, name)) if !section.is_empty() && !name.is_empty() => {
+                self.values.insert(key.to_string(), value.to_string());
+                Ok(())
+            }
+            _ => Err(format!(
+                "Config key '{}' must look like 'section.name'.",
+                key
+            )),
+        }
+    }
+
+    /// Author identity from `author.*`, falling back to `user.*`, with the
+    /// `TREFOIL_AUTHOR_NAME`/`TREFOIL_AUTHOR_EMAIL` environment variables taking precedence.
+    pub fn author(&self) -> Result<Signature, String> {
+        self.identity("author")
+    }
+
+    /// Committer identity, looked up like `author` under `committer.*`.
+    pub fn committer(&self) -> Result<Signature, String> {
+        self.identity("committer")
+    }
+
+    fn identity(&self, role: &str) -> Result<Signature, String> {
+        let lookup = |field: &str| {
+            std::env::var(format!("TREFOIL_{}_{}", role, field).to_uppercase())
+                .ok()
+                .or_else(|| self.get(&format!("{}.{}", role, field)).map(str::to_string))
+                .or_else(|| self.get(&format!("user.{}", field)).map(str::to_string))
+                .filter(|value| !value.is_empty())
+        };
+        match (lookup("name"), lookup("email")) {
+            (Some(name), Some(email)) => Ok(Signature { name, email }),
+            _ => Err(format!(
+                "{} identity unknown. Set it with:\n\n    trefoil config user.name \"Your Name\"\n    trefoil config user.email you@example.com\n\n(add --global to set it for every repository).",
+                capitalize(role)
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut section = None;
+        for (key, value) in &self.values {
+            let (current, name) = key.split_once('.').unwrap_or(("", key));
+            if section != Some(current) {
+                writeln!(f, "[{}]", current)?;
+                section = Some(current);
+            }
+            writeln!(f, "\t{} = {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+pub fn repository_config_path(vcdir: &Path) -> PathBuf {
+    vcdir.join("config")
+}
+
+/// `$XDG_CONFIG_HOME/trefoil/config`, or `~/.config/trefoil/config`.
+pub fn user_config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("trefoil").join("config"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display_config() {
+        let config = Config::parse(
+            "# identity\n[user]\n  name = Ada Lovelace\n  email = ada@example.com\n[committer]\nname = CI\n",
+        )
+        .unwrap();
+        assert_eq!(config.get("user.name"), Some("Ada Lovelace"));
+        assert_eq!(config.get("committer.name"), Some("CI"));
+        assert_eq!(Config::parse(&config.to_string()).unwrap(), config);
+        assert!(Config::parse("[user]\nname\n").is_err());
+    }
+
+    #[test]
+    fn test_identity_falls_back_to_user() {
+        let config =
+            Config::parse("[user]\nname = Ada\nemail = ada@example.com\n[committer]\nname = CI\n")
+                .unwrap();
+        let committer = config.committer().unwrap();
+        assert_eq!(committer.name, "CI");
+        assert_eq!(committer.email, "ada@example.com");
+        assert!(Config::default().author().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::vc::{Commit, load_commit};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+
+/// The commits reachable from a set of heads, indexed by id.
+pub struct CommitGraph {
+    commits: HashMap<String, Commit>,
+}
+
+impl CommitGraph {
+    /// Loads every commit reachable from `heads` through any parent.
+    pub fn load(heads: &[&str], dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut commits = HashMap::new();
+        let mut stack: Vec<String> = heads.iter().map(|id| id.to_string()).collect();
+        while let Some(id) = stack.pop() {
+            if commits.contains_key(&id) {
+                continue;
+            }
+            let commit = load_commit(&id, dir)?;
+            stack.extend(commit.parents.iter().cloned());
+            commits.insert(id, commit);
+        }
+        Ok(CommitGraph { commits })
+    }
+
+    pub fn from_commits(commits: impl IntoIterator<Item = Commit>) -> Self {
+        CommitGraph {
+            commits: commits.into_iter().map(|c| (c.id.clone(), c)).collect(),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Commit> {
+        self.commits.get(id)
+    }
+
+    /// `id` and every commit reachable from it.
+    pub fn ancestors(&self, id: &str) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut stack = vec![id.to_string()];
+        while let Some(id) = stack.pop() {
+            if seen.insert(id.clone())
+                && let Some(commit) = self.commits.get(&id)
+            {
+                stack.extend(commit.parents.iter().cloned());
+            }
+        }
+        seen
+    }
+
+    /// Whether `ancestor` is reachable from `id` (or is `id`).
+    pub fn is_ancestor(&self, ancestor: &str, id: &str) -> bool {
+        self.ancestors(id).contains(ancestor)
+    }
+
+    /// Lowest common ancestor of `a` and `b`: a common ancestor that is not an
+    /// ancestor of another common ancestor. When history criss-crosses and
+    /// there are several, the first in topological order wins.
+    pub fn merge_base(&self, a: &str, b: &str) -> Option<String> {
+        let ancestors_of_b = self.ancestors(b);
+        let common: HashSet<String> = self
+            .ancestors(a)
+            .into_iter()
+            .filter(|id| ancestors_of_b.contains(id))
+            .collect();
+        let mut best = common.clone();
+        for id in &common {
+            if let Some(commit) = self.commits.get(id) {
+                for parent in &commit.parents {
+                    for ancestor in self.ancestors(parent) {
+                        best.remove(&ancestor);
+                    }
+                }
+            }
+        }
+        self.topological_order(&[a, b])
+            .into_iter()
+            .find(|id| best.contains(id))
+    }
+
+    /// Commits reachable from `heads`, every commit before its parents. A
+    /// commit's first parent follows it as soon as no other child is pending,
+    /// so lines of descent are listed one after another rather than interleaved.
+    pub fn topological_order(&self, heads: &[&str]) -> Vec<String> {
+        let mut reachable = HashSet::new();
+        for head in heads {
+            reachable.extend(self.ancestors(head));
+        }
+        let mut children: HashMap<&str, usize> = HashMap::new();
+        for id in &reachable {
+            if let Some(commit) = self.commits.get(id) {
+                for parent in &commit.parents {
+                    *children.entry(parent.as_str()).or_default() += 1;
+                }
+            }
+        }
+
+        let mut ready: Vec<&str> = Vec::new();
+        for head in heads.iter().rev() {
+            if let Some(id) = reachable.get(*head)
+                && !children.contains_key(id.as_str())
+                && !ready.contains(&id.as_str())
+            {
+                ready.push(id);
+            }
+        }
+        let mut order = Vec::with_capacity(reachable.len());
+        while let Some(id) = ready.pop() {
+            order.push(id.to_string());
+            let Some(commit) = self.commits.get(id) else {
+                continue;
+            };
+            for parent in commit.parents.iter().rev() {
+                let remaining = children
+                    .get_mut(parent.as_str())
+                    .expect("parent was counted");
+                *remaining -= 1;
+                if *remaining == 0 {
+                    ready.push(parent);
+                }
+            }
+        }
+        order
+    }
+}
+
+/// Renders `order` (as returned by `topological_order`) as an ASCII graph,
+/// one lane per line of descent. Each row is the lane art, with `*` marking
+/// the commit; rows for a commit are paired with `Some(id)`, connector rows
+/// drawn between commits with `None`.
+pub fn render_graph(graph: &CommitGraph, order: &[String]) -> Vec<(String, Option<String>)> {
+    let mut rows = Vec::new();
+    // The commit each lane is waiting for.
+    let mut lanes: Vec<&str> = Vec::new();
+    for id in order {
+        let col = match lanes.iter().position(|lane| lane == id) {
+            Some(col) => col,
+            None => {
+                lanes.push(id);
+                lanes.len() - 1
+            }
+        };
+        let art = lanes
+            .iter()
+            .enumerate()
+            .map(|(i, _)| if i == col { "*" } else { "|" })
+            .collect::<Vec<_>>()
+            .join(" ");
+        rows.push((art, Some(id.clone())));
+
+        let parents = graph.get(id).map(|c| c.parents.as_slice()).unwrap_or(&[]);
+        match parents.split_first() {
+            None => {
+                lanes.remove(col);
+            }
+            Some((first, rest)) => {
+                lanes[col] = first;
+                for (offset, parent) in rest.iter().enumerate() {
+                    if lanes.contains(&parent.as_str()) {
+                        continue;
+                    }
+                    let new_lane = col + 1 + offset;
+                    lanes.insert(new_lane, parent);
+                    // Lanes up to the commit carry on, the rest fan out.
+                    let mut art = String::new();
+                    for i in 0..lanes.len() - 1 {
+                        art.push_str(if i < new_lane { "|" } else { " " });
+                        art.push(if i + 1 >= new_lane { '\\' } else { ' ' });
+                    }
+                    rows.push((art.trim_end().to_string(), None));
+                }
+            }
+        }
+
+        // Lanes waiting for the same commit join the leftmost one.
+        while let Some(dup) = (1..lanes.len()).find(|&i| lanes[..i].contains(&lanes[i])) {
+            let mut art = vec![' '; 2 * lanes.len()];
+            for i in 0..lanes.len() {
+                if i < dup {
+                    art[2 * i] = '|';
+                } else {
+                    art[2 * i - 1] = '/';
+                }
+            }
+            rows.push((art.iter().collect::<String>().trim_end().to_string(), None));
+            lanes.remove(dup);
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vc::CommitMetadata;
+
+    fn commit(id: &str, parents: &[&str]) -> Commit {
+        Commit {
+            id: id.to_string(),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+            instructions: vec![],
+            layout: None,
+            files: None,
+            removed_files: None,
+            metadata: CommitMetadata::default(),
+        }
+    }
+
+    /// 0 - 1 - 2 - 4 - 6
+    ///      \     /   /
+    ///       3 ------5
+    fn sample() -> CommitGraph {
+        CommitGraph::from_commits([
+            commit("0", &[]),
+            commit("1", &["0"]),
+            commit("2", &["1"]),
+            commit("3", &["1"]),
+            commit("4", &["2", "3"]),
+            commit("5", &["3"]),
+            commit("6", &["4", "5"]),
+        ])
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let graph = sample();
+        let mut ancestors: Vec<String> = graph.ancestors("4").into_iter().collect();
+        ancestors.sort();
+        assert_eq!(ancestors, vec!["0", "1", "2", "3", "4"]);
+        assert!(graph.is_ancestor("3", "6"));
+        assert!(!graph.is_ancestor("5", "4"));
+    }
+
+    #[test]
+    fn test_merge_base() {
+        let graph = sample();
+        assert_eq!(graph.merge_base("2", "3").as_deref(), Some("1"));
+        assert_eq!(graph.merge_base("4", "5").as_deref(), Some("3"));
+        assert_eq!(graph.merge_base("6", "2").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn test_topological_order() {
+        let graph = sample();
+        let order = graph.topological_order(&["6"]);
+        assert_eq!(order.len(), 7);
+        let position = |id: &str| order.iter().position(|x| x == id).unwrap();
+        for id in &order {
+            for parent in &graph.get(id).unwrap().parents {
+                assert!(position(id) < position(parent));
+            }
+        }
+        // The first-parent line is not interleaved with the side branch.
+        assert_eq!(order, vec!["6", "4", "2", "5", "3", "1", "0"]);
+    }
+
+    #[test]
+    fn test_render_graph() {
+        let graph = CommitGraph::from_commits([
+            commit("0", &[]),
+            commit("1", &["0"]),
+            commit("2", &["1"]),
+            commit("3", &["1"]),
+            commit("4", &["2", "3"]),
+        ]);
+        let order = graph.topological_order(&["4"]);
+        let art: Vec<String> = render_graph(&graph, &order)
+            .into_iter()
+            .map(|(art, _)| art)
+            .collect();
+        assert_eq!(art, vec!["*", "|\\", "* |", "| *", "|/", "*", "*"]);
+    }
+}
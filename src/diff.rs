@@ -1,66 +1,614 @@
+use crate::apply::{apply_all, apply_instruction_mut};
 use crate::ast::Ast;
 use crate::instruction::Instruction;
+use crate::transform::rebase;
+use clap::ValueEnum;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Strategy used to compute the instructions between two ASTs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DiffAlgorithm {
+    /// LCS alignment of each list's children (`diff_ast`).
+    #[default]
+    Fast,
+    /// Cheapest alignment of each list's children by subtree edit cost
+    /// (`diff_top_down`).
+    TopDown,
+}
+
+/// Diffs two ASTs from the root with the given algorithm.
+pub fn diff(old: &Ast, new: &Ast, algorithm: DiffAlgorithm) -> Vec<Instruction> {
+    let mut path = vec![];
+    match algorithm {
+        DiffAlgorithm::Fast => diff_ast(old, new, &mut path),
+        DiffAlgorithm::TopDown => diff_top_down(old, new, &mut path),
+    }
+}
 
 pub fn diff_ast(old: &Ast, new: &Ast, path: &mut Vec<usize>) -> Vec<Instruction> {
+    let instructions = diff_with(old, new, path, &align_children);
+    pair_moves(old, new, path, instructions)
+}
+
+/// Computes the cheapest top-down edit script from `old` to `new`.
+///
+/// This is Selkow's top-down tree edit distance: updating an atom costs 1,
+/// inserting or deleting a subtree costs its node count, and a node is only
+/// ever paired with a node at the same position in the list it belongs to.
+/// Every list is aligned with a full edit-distance table whose substitution
+/// cost is the distance between the two children, so unlike `diff_ast` the
+/// pairing of unequal children is chosen by cost rather than by position.
+/// Unlike a general tree edit distance it cannot remove a list while keeping
+/// its items, or wrap items in a new one. Distances between subtrees are
+/// memoized, so each pair of subtrees is compared once per diff.
+pub fn diff_top_down(old: &Ast, new: &Ast, path: &mut Vec<usize>) -> Vec<Instruction> {
+    let distances = Distances::default();
+    let align = |old: &[Ast], new: &[Ast]| align_by_cost(old, new, &distances);
+    let instructions = diff_with(old, new, path, &align);
+    pair_moves(old, new, path, instructions)
+}
+
+/// How the children of two lists correspond.
+#[derive(Default)]
+struct Alignment {
+    /// `(old_index, new_index)` pairs kept in place, increasing in both.
+    pairs: Vec<(usize, usize)>,
+    /// `(old_index, new_index)` pairs whose child changes position relative
+    /// to the `pairs`, sorted by new index.
+    moves: Vec<(usize, usize)>,
+}
+
+type Aligner<'a> = &'a dyn Fn(&[Ast], &[Ast]) -> Alignment;
+
+fn diff_with(old: &Ast, new: &Ast, path: &mut Vec<usize>, align: Aligner) -> Vec<Instruction> {
     match (old, new) {
-        (Ast::Atom(a), Ast::Atom(b)) => {
-            if a != b {
-                vec![Instruction::Update {
-                    path: path.clone(),
-                    new_value: b.clone(),
-                }]
-            } else {
-                vec![]
-            }
+        (Ast::Atom(a), Ast::Atom(b)) | (Ast::Str(a), Ast::Str(b)) => update(a, b, path),
+        (Ast::Literal(old_kind, a), Ast::Literal(new_kind, b)) if old_kind == new_kind => {
+            update(a, b, path)
         }
-        (Ast::List(old_children), Ast::List(new_children)) => {
-            if old_children.len() == new_children.len() {
-                let mut instructions = Vec::new();
-                for (i, (old_child, new_child)) in
-                    old_children.iter().zip(new_children.iter()).enumerate()
-                {
-                    path.push(i);
-                    instructions.extend(diff_ast(old_child, new_child, path));
-                    path.pop();
-                }
-                instructions
-            } else {
-                vec![Instruction::Replace {
-                    path: path.clone(),
-                    node: new.clone(),
-                }]
-            }
+        (Ast::List(old_delimiter, old_children), Ast::List(new_delimiter, new_children))
+            if old_delimiter == new_delimiter =>
+        {
+            diff_children(old_children, new_children, path, align)
         }
+        (Ast::Quoted(old_macro, old_form), Ast::Quoted(new_macro, new_form))
+            if old_macro == new_macro =>
+        {
+            path.push(0);
+            let instructions = diff_with(old_form, new_form, path, align);
+            path.pop();
+            instructions
+        }
+        _ if old == new => vec![],
         _ => {
             vec![Instruction::Replace {
                 path: path.clone(),
                 node: new.clone(),
+                old: Some(Box::new(old.clone())),
             }]
         }
     }
 }
 
+/// Turns a form deleted from one list and inserted unchanged into another,
+/// such as one moved into a `let` body, into a single `Move`. Alignment only
+/// sees one list at a time, so it cannot find these itself. Each pair is kept
+/// only if the script still turns `old` into `new`. Scripts for a subtree at
+/// a non-empty `path` are left as they are.
+fn pair_moves(
+    old: &Ast,
+    new: &Ast,
+    path: &[usize],
+    mut instructions: Vec<Instruction>,
+) -> Vec<Instruction> {
+    if !path.is_empty() {
+        return instructions;
+    }
+    let mut k = 0;
+    while k < instructions.len() {
+        let paired = moved_form(&instructions[k]).and_then(|form| {
+            let partner = (k + 1..instructions.len()).find(|&m| {
+                moved_form(&instructions[m]) == Some(form) && instructions[m] != instructions[k]
+            })?;
+            pair_move(old, new, &instructions, k, partner)
+        });
+        match paired {
+            Some(paired) => instructions = paired,
+            None => k += 1,
+        }
+    }
+    instructions
+}
+
+/// The non-empty list or quoted form a `Delete` or `Insert` takes out or
+/// puts in; smaller nodes are not worth a move.
+fn moved_form(instruction: &Instruction) -> Option<&Ast> {
+    let node = match instruction {
+        Instruction::Delete {
+            node: Some(node), ..
+        } => &**node,
+        Instruction::Insert { node, .. } => node,
+        _ => return None,
+    };
+    match node {
+        Ast::List(_, items) if !items.is_empty() => Some(node),
+        Ast::Quoted(..) => Some(node),
+        _ => None,
+    }
+}
+
+/// `instructions` with the delete and the insert at `k` and `m` (in either
+/// order) replaced by one `Move` where the later one was. The instructions
+/// in between are rebased to see the form where it still is, which a
+/// placeholder tracks. `None` if that fails or changes the result.
+fn pair_move(
+    old: &Ast,
+    new: &Ast,
+    instructions: &[Instruction],
+    k: usize,
+    m: usize,
+) -> Option<Vec<Instruction>> {
+    let placeholder = Ast::Error("\u{0}moved".to_string());
+    let mut before = old.clone();
+    apply_all(&mut before, &instructions[..k]).ok()?;
+    let (between, from, to) = match (&instructions[k], &instructions[m]) {
+        (
+            Instruction::Delete { path, index, .. },
+            Instruction::Insert {
+                path: to_path,
+                index: to_index,
+                ..
+            },
+        ) => {
+            // Leave the form in place until the insert.
+            let keep = Instruction::Insert {
+                path: path.clone(),
+                index: *index,
+                node: placeholder.clone(),
+            };
+            let between = rebase(&instructions[k + 1..m], std::slice::from_ref(&keep)).ok()?;
+            let mut marked = before;
+            apply_instruction_mut(&mut marked, &instructions[k]).ok()?;
+            apply_instruction_mut(&mut marked, &keep).ok()?;
+            apply_all(&mut marked, &between).ok()?;
+            let from = find(&marked, &placeholder)?;
+            (between, from, (to_path.clone(), *to_index))
+        }
+        (Instruction::Insert { path, index, .. }, Instruction::Delete { .. }) => {
+            // Leave the form out until the delete, which becomes the move.
+            let skip = Instruction::Delete {
+                path: path.clone(),
+                index: *index,
+                node: None,
+            };
+            let mut rebased = rebase(&instructions[k + 1..=m], &[skip]).ok()?;
+            let Some(Instruction::Delete {
+                path: from_path,
+                index: from_index,
+                ..
+            }) = rebased.pop()
+            else {
+                return None;
+            };
+            let mark = Instruction::Insert {
+                path: path.clone(),
+                index: *index,
+                node: placeholder.clone(),
+            };
+            let mut marked = before;
+            apply_instruction_mut(&mut marked, &mark).ok()?;
+            apply_all(&mut marked, &instructions[k + 1..=m]).ok()?;
+            let to = find(&marked, &placeholder)?;
+            (rebased, (from_path, from_index), to)
+        }
+        _ => return None,
+    };
+
+    let mut paired = instructions[..k].to_vec();
+    paired.extend(between);
+    paired.push(Instruction::Move {
+        from_path: from.0,
+        from_index: from.1,
+        to_path: to.0,
+        to_index: to.1,
+    });
+    paired.extend_from_slice(&instructions[m + 1..]);
+    let mut check = old.clone();
+    apply_all(&mut check, &paired).ok()?;
+    (check == *new).then_some(paired)
+}
+
+/// The parent path and index of the first node equal to `target`.
+fn find(ast: &Ast, target: &Ast) -> Option<(Vec<usize>, usize)> {
+    for (i, child) in ast.children().iter().enumerate() {
+        if child == target {
+            return Some((vec![], i));
+        }
+        if let Some((mut path, index)) = find(child, target) {
+            path.insert(0, i);
+            return Some((path, index));
+        }
+    }
+    None
+}
+
+/// `Update` for a leaf whose text changes from `a` to `b`, keeping its kind.
+fn update(a: &str, b: &str, path: &[usize]) -> Vec<Instruction> {
+    if a != b {
+        vec![Instruction::Update {
+            path: path.to_vec(),
+            new_value: b.to_string(),
+            old_value: Some(a.to_string()),
+        }]
+    } else {
+        vec![]
+    }
+}
+
+/// Diffs the children of a list at `path`, emitting `Delete`/`Insert` for
+/// unaligned children, `Move` for moved ones and recursing into the rest.
+fn diff_children(
+    old: &[Ast],
+    new: &[Ast],
+    path: &mut Vec<usize>,
+    align: Aligner,
+) -> Vec<Instruction> {
+    let Alignment { pairs, moves } = align(old, new);
+    let mut instructions = Vec::new();
+
+    // Deletes go first, highest index first, so the remaining indices stay valid.
+    let mut source = vec![None; new.len()];
+    let mut matched_old = vec![false; old.len()];
+    for &(i, j) in pairs.iter().chain(&moves) {
+        source[j] = Some(i);
+        matched_old[i] = true;
+    }
+    for index in (0..old.len()).rev().filter(|&i| !matched_old[i]) {
+        instructions.push(Instruction::Delete {
+            path: path.clone(),
+            index,
+            node: Some(Box::new(old[index].clone())),
+        });
+    }
+
+    // Moved children are placed right after their predecessor in the new
+    // order, leaving the matched old children in new order.
+    let mut current: Vec<usize> = (0..old.len()).filter(|&i| matched_old[i]).collect();
+    for &(i, j) in &moves {
+        let from = current.iter().position(|&c| c == i).unwrap();
+        let to = match source[..j].iter().rev().flatten().next() {
+            Some(pred) => {
+                let pred_pos = current.iter().position(|c| c == pred).unwrap();
+                if from <= pred_pos {
+                    pred_pos
+                } else {
+                    pred_pos + 1
+                }
+            }
+            None => 0,
+        };
+        if from != to {
+            current.remove(from);
+            current.insert(to, i);
+            instructions.push(Instruction::Move {
+                from_path: path.clone(),
+                from_index: from,
+                to_path: path.clone(),
+                to_index: to,
+            });
+        }
+    }
+
+    // Walking the new children left to right now puts every surviving child
+    // at its final index.
+    for (j, new_child) in new.iter().enumerate() {
+        match source[j] {
+            Some(i) => {
+                path.push(j);
+                instructions.extend(diff_with(&old[i], new_child, path, align));
+                path.pop();
+            }
+            None => instructions.push(Instruction::Insert {
+                path: path.clone(),
+                index: j,
+                node: new_child.clone(),
+            }),
+        }
+    }
+    instructions
+}
+
+/// Aligns two child lists in the spirit of GumTree.
+///
+/// Identical children are anchored in place with a longest common
+/// subsequence. Top-down, the remaining identical children are matched by
+/// hash as moves. The unanchored children between two anchors are then paired
+/// up positionally so that small edits inside a form recurse instead of
+/// replacing it. Finally, bottom-up, leftover lists that share most of their
+/// atoms are matched as moves too.
+fn align_children(old: &[Ast], new: &[Ast]) -> Alignment {
+    let anchors = lcs(old, new);
+    let mut old_free = vec![true; old.len()];
+    let mut new_free = vec![true; new.len()];
+    for &(i, j) in &anchors {
+        old_free[i] = false;
+        new_free[j] = false;
+    }
+
+    let mut moves = Vec::new();
+    let mut by_hash: HashMap<&Ast, Vec<usize>> = HashMap::new();
+    for i in (0..old.len()).rev().filter(|&i| old_free[i]) {
+        by_hash.entry(&old[i]).or_default().push(i);
+    }
+    for j in 0..new.len() {
+        if !new_free[j] {
+            continue;
+        }
+        if let Some(i) = by_hash
+            .get_mut(&new[j])
+            .and_then(|candidates| candidates.pop())
+        {
+            old_free[i] = false;
+            new_free[j] = false;
+            moves.push((i, j));
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut old_start, mut new_start) = (0, 0);
+    for (i, j) in anchors.into_iter().chain([(old.len(), new.len())]) {
+        let old_gap: Vec<usize> = (old_start..i).filter(|&k| old_free[k]).collect();
+        let new_gap: Vec<usize> = (new_start..j).filter(|&k| new_free[k]).collect();
+        // The shorter side slides along the longer one to where its lists
+        // are most alike, so that an edited form pairs with itself rather
+        // than with a sibling that left.
+        let offsets = |offset: usize| {
+            let (old_skip, new_skip) = if old_gap.len() > new_gap.len() {
+                (offset, 0)
+            } else {
+                (0, offset)
+            };
+            old_gap[old_skip..]
+                .iter()
+                .copied()
+                .zip(new_gap[new_skip..].iter().copied())
+        };
+        let score = |offset| {
+            offsets(offset)
+                .map(|(oi, nj)| similarity(&old[oi], &new[nj]))
+                .sum::<f64>()
+        };
+        let best = (0..=old_gap.len().abs_diff(new_gap.len()))
+            .max_by(|a, b| score(*a).total_cmp(&score(*b)).then(b.cmp(a)))
+            .unwrap_or(0);
+        for (oi, nj) in offsets(best).collect::<Vec<_>>() {
+            old_free[oi] = false;
+            new_free[nj] = false;
+            pairs.push((oi, nj));
+        }
+        if i < old.len() {
+            pairs.push((i, j));
+        }
+        old_start = i + 1;
+        new_start = j + 1;
+    }
+
+    for j in (0..new.len()).filter(|&j| new_free[j]) {
+        let best = (0..old.len())
+            .filter(|&i| old_free[i])
+            .map(|i| (i, similarity(&old[i], &new[j])))
+            .filter(|&(_, score)| score >= MOVE_SIMILARITY)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, _)) = best {
+            old_free[i] = false;
+            moves.push((i, j));
+        }
+    }
+
+    moves.sort_by_key(|&(_, j)| j);
+    Alignment { pairs, moves }
+}
+
+/// Minimum `similarity` for two unaligned lists to be treated as one moved
+/// and edited form.
+const MOVE_SIMILARITY: f64 = 0.5;
+
+/// Dice coefficient of the atoms of two lists; 0 unless both are lists.
+pub(crate) fn similarity(old: &Ast, new: &Ast) -> f64 {
+    fn atoms<'a>(ast: &'a Ast, counts: &mut HashMap<&'a str, usize>) {
+        match ast {
+            Ast::Atom(a) | Ast::Str(a) | Ast::Literal(_, a) => *counts.entry(a).or_default() += 1,
+            _ => ast.children().iter().for_each(|c| atoms(c, counts)),
+        }
+    }
+    if !matches!((old, new), (Ast::List(..), Ast::List(..))) {
+        return 0.0;
+    }
+    let (mut old_atoms, mut new_atoms) = (HashMap::new(), HashMap::new());
+    atoms(old, &mut old_atoms);
+    atoms(new, &mut new_atoms);
+    let total: usize = old_atoms.values().chain(new_atoms.values()).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let common: usize = old_atoms
+        .iter()
+        .map(|(atom, &count)| count.min(new_atoms.get(atom).copied().unwrap_or(0)))
+        .sum();
+    2.0 * common as f64 / total as f64
+}
+
+/// Longest common subsequence of two slices, as `(old_index, new_index)` pairs.
+pub(crate) fn lcs<T: PartialEq>(old: &[T], new: &[T]) -> Vec<(usize, usize)> {
+    // Common prefixes and suffixes are matched directly to keep the table small.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let (n, m) = (old_mid.len(), new_mid.len());
+    let mut table = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[i][j] = if old_mid[i] == new_mid[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|k| (k, k)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old_mid[i] == new_mid[j] {
+            pairs.push((prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        } else if table[i + 1][j] >= table[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs.extend((0..suffix).map(|k| (old.len() - suffix + k, new.len() - suffix + k)));
+    pairs
+}
+
+/// Aligns two child lists by minimum total edit cost. Moves are not
+/// considered.
+fn align_by_cost(old: &[Ast], new: &[Ast], distances: &Distances) -> Alignment {
+    let table = edit_table(old, new, distances);
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (old.len(), new.len());
+    while i > 0 && j > 0 {
+        if table[i][j] == table[i - 1][j] + size(&old[i - 1]) {
+            i -= 1;
+        } else if table[i][j] == table[i][j - 1] + size(&new[j - 1]) {
+            j -= 1;
+        } else {
+            pairs.push((i - 1, j - 1));
+            i -= 1;
+            j -= 1;
+        }
+    }
+    pairs.reverse();
+    Alignment {
+        pairs,
+        moves: vec![],
+    }
+}
+
+/// `table[i][j]` is the cheapest way to turn `old[..i]` into `new[..j]`.
+fn edit_table(old: &[Ast], new: &[Ast], distances: &Distances) -> Vec<Vec<usize>> {
+    let (n, m) = (old.len(), new.len());
+    let mut table = vec![vec![0usize; m + 1]; n + 1];
+    for i in 1..=n {
+        table[i][0] = table[i - 1][0] + size(&old[i - 1]);
+    }
+    for j in 1..=m {
+        table[0][j] = table[0][j - 1] + size(&new[j - 1]);
+    }
+    for i in 1..=n {
+        for j in 1..=m {
+            let delete = table[i - 1][j] + size(&old[i - 1]);
+            let insert = table[i][j - 1] + size(&new[j - 1]);
+            let pair = table[i - 1][j - 1] + distances.get(&old[i - 1], &new[j - 1]);
+            table[i][j] = delete.min(insert).min(pair);
+        }
+    }
+    table
+}
+
+/// `tree_distance`s already computed, keyed by the addresses of the two
+/// nodes, which stay borrowed for as long as one diff runs.
+#[derive(Default)]
+struct Distances(RefCell<HashMap<(*const Ast, *const Ast), usize>>);
+
+impl Distances {
+    fn get(&self, old: &Ast, new: &Ast) -> usize {
+        let key = (old as *const Ast, new as *const Ast);
+        if let Some(&distance) = self.0.borrow().get(&key) {
+            return distance;
+        }
+        let distance = tree_distance(old, new, self);
+        self.0.borrow_mut().insert(key, distance);
+        distance
+    }
+}
+
+/// Cost of the script `diff_top_down` would produce between two trees.
+fn tree_distance(old: &Ast, new: &Ast, distances: &Distances) -> usize {
+    if old == new {
+        return 0;
+    }
+    match (old, new) {
+        (Ast::Atom(_), Ast::Atom(_)) | (Ast::Str(_), Ast::Str(_)) => 1,
+        (Ast::Literal(old_kind, _), Ast::Literal(new_kind, _)) if old_kind == new_kind => 1,
+        (Ast::List(old_delimiter, old_children), Ast::List(new_delimiter, new_children))
+            if old_delimiter == new_delimiter =>
+        {
+            edit_table(old_children, new_children, distances)[old_children.len()]
+                [new_children.len()]
+        }
+        (Ast::Quoted(old_macro, old_form), Ast::Quoted(new_macro, new_form))
+            if old_macro == new_macro =>
+        {
+            distances.get(old_form, new_form)
+        }
+        _ => size(old) + size(new),
+    }
+}
+
+/// Number of nodes in a tree.
+fn size(ast: &Ast) -> usize {
+    1 + ast.children().iter().map(size).sum::<usize>()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::apply::apply_instruction;
+    use crate::apply::{apply_all, apply_instruction, enrich};
+    use crate::ast::{Delimiter, ReaderMacro, Trivia};
+    use crate::instruction::invert_all;
+    use crate::parser::classify_atom;
     use proptest::prelude::*;
     use proptest::strategy::{BoxedStrategy, Strategy};
 
     fn atom_strategy() -> BoxedStrategy<Ast> {
-        prop::string::string_regex("[a-zA-Z0-9_]+")
-            .unwrap()
-            .prop_map(|s| Ast::Atom(s))
-            .boxed()
+        prop_oneof![
+            8 => prop::string::string_regex("[a-zA-Z0-9_]+")
+                .unwrap()
+                .prop_map(|text| classify_atom(&text)),
+            1 => prop::string::string_regex("[ a-z]*")
+                .unwrap()
+                .prop_map(|text| Ast::Trivia(Trivia::LineComment(text))),
+            1 => Just(Ast::Trivia(Trivia::BlankLine)),
+            2 => any::<String>().prop_map(Ast::Str),
+        ]
+        .boxed()
     }
 
     fn list_strategy(depth: u32) -> BoxedStrategy<Ast> {
         if depth == 0 {
             atom_strategy()
         } else {
-            prop::collection::vec(ast_strategy(depth - 1), 0..5)
-                .prop_map(|v| Ast::List(v))
-                .boxed()
+            prop_oneof![
+                4 => (
+                    prop::sample::select(Delimiter::ALL.to_vec()),
+                    prop::collection::vec(ast_strategy(depth - 1), 0..5)
+                )
+                    .prop_map(|(delimiter, items)| Ast::List(delimiter, items)),
+                1 => (prop::sample::select(ReaderMacro::ALL.to_vec()), ast_strategy(depth - 1))
+                    .prop_map(|(reader_macro, form)| Ast::Quoted(reader_macro, Box::new(form))),
+            ]
+            .boxed()
         }
     }
 
@@ -75,28 +623,302 @@ mod tests {
     proptest! {
         #[test]
         fn diff_apply_roundtrip(old in ast_strategy(3), new in ast_strategy(3)) {
-            let mut path = vec![];
-            let instructions = diff_ast(&old, &new, &mut path);
+            for &algorithm in DiffAlgorithm::value_variants() {
+                let instructions = diff(&old, &new, algorithm);
 
-            // Apply all instructions to the old AST
-            let mut result = old.clone();
-            for instruction in instructions {
-                result = apply_instruction(result, instruction);
+                // Apply all instructions to the old AST
+                let mut result = old.clone();
+                for instruction in instructions {
+                    result = apply_instruction(result, instruction).unwrap();
+                }
+
+                // The result should equal the new AST
+                assert_eq!(&result, &new, "algorithm {:?}", algorithm);
+            }
+        }
+
+        #[test]
+        fn diff_invert_roundtrip(old in ast_strategy(3), new in ast_strategy(3)) {
+            for &algorithm in DiffAlgorithm::value_variants() {
+                let instructions = diff(&old, &new, algorithm);
+                let mut result = new.clone();
+                apply_all(&mut result, &invert_all(&instructions).unwrap()).unwrap();
+                assert_eq!(&result, &old, "algorithm {:?}", algorithm);
+
+                // What is recorded can be found again from the old AST.
+                let bare: Vec<Instruction> = instructions
+                    .iter()
+                    .cloned()
+                    .map(|mut instruction| {
+                        match &mut instruction {
+                            Instruction::Delete { node, .. } => *node = None,
+                            Instruction::Update { old_value, .. } => *old_value = None,
+                            Instruction::Replace { old, .. } => *old = None,
+                            Instruction::Insert { .. } | Instruction::Move { .. } => {}
+                        }
+                        instruction
+                    })
+                    .collect();
+                assert_eq!(enrich(&old, &bare).unwrap(), instructions);
             }
+        }
 
-            // The result should equal the new AST
+        #[test]
+        fn diff_shuffle_roundtrip(
+            (old, new) in prop::collection::vec(ast_strategy(2), 0..6)
+                .prop_flat_map(|v| (Just(v.clone()), Just(v).prop_shuffle()))
+        ) {
+            let (old, new) = (Ast::list(old), Ast::list(new));
+            let mut result = old.clone();
+            for instruction in diff_ast(&old, &new, &mut vec![]) {
+                assert!(!matches!(instruction, Instruction::Insert { .. }));
+                result = apply_instruction(result, instruction).unwrap();
+            }
             assert_eq!(result, new);
         }
 
         #[test]
         fn diff_minimal(old in ast_strategy(3), new in ast_strategy(3)) {
-            let mut path = vec![];
-            let instructions = diff_ast(&old, &new, &mut path);
+            for &algorithm in DiffAlgorithm::value_variants() {
+                let instructions = diff(&old, &new, algorithm);
+
+                // If old and new are equal, there should be no instructions
+                if old == new {
+                    assert!(instructions.is_empty());
+                }
+            }
+        }
+    }
+
+    fn atom(s: &str) -> Ast {
+        Ast::Atom(s.to_string())
+    }
+
+    fn form(items: &[&str]) -> Ast {
+        Ast::list(items.iter().map(|s| atom(s)).collect())
+    }
+
+    #[test]
+    fn test_diff_appended_form_is_insert() {
+        let old = Ast::list(vec![form(&["define", "x", "10"]), form(&["print", "x"])]);
+        let new = Ast::list(vec![
+            form(&["define", "x", "10"]),
+            form(&["print", "x"]),
+            form(&["display", "done"]),
+        ]);
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        assert_eq!(instructions.len(), 1);
+        assert!(matches!(
+            &instructions[0],
+            Instruction::Insert { path, index: 2, .. } if path.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_diff_removed_form_is_delete() {
+        let old = Ast::list(vec![form(&["a"]), form(&["b"]), form(&["c"])]);
+        let new = Ast::list(vec![form(&["a"]), form(&["c"])]);
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        assert_eq!(instructions.len(), 1);
+        assert!(matches!(
+            &instructions[0],
+            Instruction::Delete { path, index: 1, .. } if path.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_diff_recurses_into_aligned_children() {
+        let old = Ast::list(vec![form(&["define", "x", "10"]), form(&["print", "x"])]);
+        let new = Ast::list(vec![
+            form(&["comment"]),
+            form(&["define", "x", "10"]),
+            form(&["print", "y"]),
+        ]);
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        assert_eq!(instructions.len(), 2);
+        assert!(matches!(
+            &instructions[0],
+            Instruction::Insert { index: 0, .. }
+        ));
+        assert!(matches!(
+            &instructions[1],
+            Instruction::Update { path, new_value, .. } if path == &vec![2, 1] && new_value == "y"
+        ));
+    }
+
+    #[test]
+    fn test_diff_top_down_pairs_by_cost() {
+        // Positional pairing would replace both children; the top-down script
+        // inserts `y`, extends the list and deletes `x` instead.
+        let old = Ast::list(vec![form(&["a", "b", "c"]), atom("x")]);
+        let new = Ast::list(vec![atom("y"), form(&["a", "b", "c", "d"])]);
+
+        let fast = diff(&old, &new, DiffAlgorithm::Fast);
+        assert!(
+            fast.iter()
+                .any(|i| matches!(i, Instruction::Replace { .. }))
+        );
+
+        let top_down = diff(&old, &new, DiffAlgorithm::TopDown);
+        assert_eq!(top_down.len(), 3);
+        assert!(
+            !top_down
+                .iter()
+                .any(|i| matches!(i, Instruction::Replace { .. }))
+        );
+        assert_eq!(tree_distance(&old, &new, &Distances::default()), 3);
+    }
+
+    #[test]
+    fn test_diff_reordered_forms_are_moves() {
+        let a = form(&["define", "a", "1"]);
+        let b = form(&["define", "b", "2"]);
+        let c = form(&["define", "c", "3"]);
+        let old = Ast::list(vec![a.clone(), b.clone(), c.clone()]);
+        let new = Ast::list(vec![c, a, b]);
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        assert_eq!(instructions.len(), 1);
+        assert!(matches!(
+            &instructions[0],
+            Instruction::Move {
+                from_index: 2,
+                to_index: 0,
+                ..
+            }
+        ));
+    }
 
-            // If old and new are equal, there should be no instructions
-            if old == new {
-                assert!(instructions.is_empty());
+    #[test]
+    fn test_diff_form_moved_across_lists() {
+        use crate::parser::{parse, tokenize};
+        let outside = parse(&tokenize("(define (f x) (display x) (let ((y 1)) (g y)))")).unwrap();
+        let inside = parse(&tokenize("(define (f x) (let ((y 1)) (display x) (g y)))")).unwrap();
+        for &algorithm in DiffAlgorithm::value_variants() {
+            // Into the `let` body, and back out.
+            for (old, new, from, to) in [
+                (&outside, &inside, (vec![0], 2), (vec![0, 2], 2)),
+                (&inside, &outside, (vec![0, 2], 2), (vec![0], 2)),
+            ] {
+                let instructions = diff(old, new, algorithm);
+                assert_eq!(
+                    instructions,
+                    vec![Instruction::Move {
+                        from_path: from.0,
+                        from_index: from.1,
+                        to_path: to.0,
+                        to_index: to.1,
+                    }],
+                    "algorithm {:?}",
+                    algorithm
+                );
             }
         }
     }
+
+    #[test]
+    fn test_diff_moved_and_edited_form() {
+        let old = Ast::list(vec![
+            form(&["define", "square", "x", "x"]),
+            form(&["print", "a"]),
+            form(&["print", "b"]),
+        ]);
+        let new = Ast::list(vec![
+            form(&["print", "a"]),
+            form(&["print", "b"]),
+            form(&["define", "square", "x", "y"]),
+        ]);
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        assert_eq!(instructions.len(), 2);
+        assert!(matches!(
+            &instructions[0],
+            Instruction::Move {
+                from_index: 0,
+                to_index: 2,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &instructions[1],
+            Instruction::Update { path, .. } if path == &vec![2, 3]
+        ));
+    }
+
+    #[test]
+    fn test_edited_comment_is_replaced_in_place() {
+        let comment = |text: &str| Ast::Trivia(Trivia::LineComment(text.to_string()));
+        let old = Ast::list(vec![comment(" old"), form(&["define", "x", "10"])]);
+        let new = Ast::list(vec![comment(" new"), form(&["define", "x", "10"])]);
+        assert_eq!(
+            diff_ast(&old, &new, &mut vec![]),
+            vec![Instruction::Replace {
+                path: vec![0],
+                node: comment(" new"),
+                old: Some(Box::new(comment(" old"))),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_edited_string_is_updated() {
+        let old = Ast::list(vec![Ast::list(vec![
+            atom("display"),
+            Ast::Str("hello".to_string()),
+        ])]);
+        let new = Ast::list(vec![Ast::list(vec![
+            atom("display"),
+            Ast::Str("hello (world)".to_string()),
+        ])]);
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        assert_eq!(
+            instructions,
+            vec![Instruction::Update {
+                path: vec![0, 1],
+                new_value: "hello (world)".to_string(),
+                old_value: Some("hello".to_string()),
+            }]
+        );
+        let mut result = old;
+        for instruction in instructions {
+            result = apply_instruction(result, instruction).unwrap();
+        }
+        assert_eq!(result, new);
+    }
+
+    #[test]
+    fn test_edit_outside_broken_code_stays_local() {
+        use crate::parser::{parse_recovering, tokenize};
+        let old = parse_recovering(&tokenize("(define x 1)\n(let [x 1)")).0;
+        let new = parse_recovering(&tokenize("(define x 2)\n(let [x 1)")).0;
+        assert_eq!(
+            diff_ast(&old, &new, &mut vec![]),
+            vec![Instruction::Update {
+                path: vec![0, 2],
+                new_value: "2".to_string(),
+                old_value: Some("1".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_edit_under_reader_macro_recurses() {
+        let quoted = |items| Ast::Quoted(ReaderMacro::Quote, Box::new(Ast::list(items)));
+        let old = Ast::list(vec![quoted(vec![atom("a"), atom("b")])]);
+        let new = Ast::list(vec![quoted(vec![atom("a"), atom("c")])]);
+        assert_eq!(
+            diff_ast(&old, &new, &mut vec![]),
+            vec![Instruction::Update {
+                path: vec![0, 0, 1],
+                new_value: "c".to_string(),
+                old_value: Some("b".to_string()),
+            }]
+        );
+        let unquoted = Ast::list(vec![Ast::Quoted(
+            ReaderMacro::Unquote,
+            Box::new(Ast::list(vec![atom("a"), atom("b")])),
+        )]);
+        assert!(matches!(
+            diff_ast(&old, &unquoted, &mut vec![])[..],
+            [Instruction::Replace { .. }]
+        ));
+    }
 }
@@ -1,8 +1,10 @@
-use crate::ast::Ast;
+use crate::apply::apply_instruction_mut;
+use crate::ast::{Ast, LiteralKind};
+use crate::locator::locate;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter, Result};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Instruction {
     Insert {
         path: Vec<usize>,
@@ -12,14 +14,33 @@ pub enum Instruction {
     Delete {
         path: Vec<usize>,
         index: usize,
+        /// The deleted node. Instructions from before it was recorded lack it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        node: Option<Box<Ast>>,
     },
+    /// Sets the text of the leaf at `path`. Strings stay strings; symbols and
+    /// literals take the kind their new text reads as.
     Update {
         path: Vec<usize>,
         new_value: String,
+        /// The text before the update, if recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        old_value: Option<String>,
     },
     Replace {
         path: Vec<usize>,
         node: Ast,
+        /// The node replaced, if recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        old: Option<Box<Ast>>,
+    },
+    /// Removes the child at `from_path`/`from_index` and inserts it at
+    /// `to_path`/`to_index`, where `to_path` is resolved after the removal.
+    Move {
+        from_path: Vec<usize>,
+        from_index: usize,
+        to_path: Vec<usize>,
+        to_index: usize,
     },
 }
 
@@ -29,15 +50,271 @@ impl Display for Instruction {
             Instruction::Insert { path, index, node } => {
                 write!(f, "Insert at path {:?} index {} node {}", path, index, node)
             }
-            Instruction::Delete { path, index } => {
+            Instruction::Delete { path, index, .. } => {
                 write!(f, "Delete at path {:?} index {}", path, index)
             }
-            Instruction::Update { path, new_value } => {
-                write!(f, "Update at path {:?} with value {}", path, new_value)
-            }
-            Instruction::Replace { path, node } => {
+            Instruction::Update {
+                path, new_value, ..
+            } => write!(f, "Update at path {:?} with value {}", path, new_value),
+            Instruction::Replace { path, node, .. } => {
                 write!(f, "Replace at path {:?} with {}", path, node)
             }
+            Instruction::Move {
+                from_path,
+                from_index,
+                to_path,
+                to_index,
+            } => write!(
+                f,
+                "Move from path {:?} index {} to path {:?} index {}",
+                from_path, from_index, to_path, to_index
+            ),
+        }
+    }
+}
+
+impl Instruction {
+    /// What the instruction does to `before`, the tree it applies to, in words:
+    /// `symbol renamed x → y`, `numeric literal changed 10 → 20`, ...
+    pub fn describe(&self, before: &Ast) -> String {
+        match self {
+            Instruction::Insert { node, .. } => format!("inserted {}", node),
+            Instruction::Delete { path, index, .. } => match child(before, path, *index) {
+                Some(node) => format!("deleted {}", node),
+                None => "deleted a node".to_string(),
+            },
+            Instruction::Update {
+                path, new_value, ..
+            } => match before.get(path) {
+                Some(Ast::Atom(old)) => format!("symbol renamed {} → {}", old, new_value),
+                Some(Ast::Literal(kind, old)) => describe_literal(*kind, old, *kind, new_value),
+                Some(Ast::Str(old)) => format!(
+                    "string changed {} → {}",
+                    Ast::Str(old.clone()),
+                    Ast::Str(new_value.clone())
+                ),
+                _ => format!("value set to {}", new_value),
+            },
+            Instruction::Replace { path, node, .. } => match (before.get(path), node) {
+                (Some(Ast::Literal(old_kind, old)), Ast::Literal(new_kind, new)) => {
+                    describe_literal(*old_kind, old, *new_kind, new)
+                }
+                (Some(old), _) => format!("replaced {} with {}", old, node),
+                (None, _) => format!("replaced a node with {}", node),
+            },
+            Instruction::Move {
+                from_path,
+                from_index,
+                ..
+            } => match child(before, from_path, *from_index) {
+                Some(node) => format!("moved {}", node),
+                None => "moved a node".to_string(),
+            },
+        }
+    }
+
+    /// The instruction as `Display` prints it, with its paths given as
+    /// locators into `before`, the tree it applies to.
+    pub fn locate_in(&self, before: &Ast) -> String {
+        let at = |tree, path: &[usize], index| locate(tree, &[path, &[index]].concat());
+        match self {
+            Instruction::Insert { path, index, node } => {
+                format!("Insert at {} node {}", at(before, path, *index), node)
+            }
+            Instruction::Delete { path, index, .. } => {
+                format!("Delete at {}", at(before, path, *index))
+            }
+            Instruction::Update {
+                path, new_value, ..
+            } => {
+                format!(
+                    "Update at {} with value {}",
+                    locate(before, path),
+                    new_value
+                )
+            }
+            Instruction::Replace { path, node, .. } => {
+                format!("Replace at {} with {}", locate(before, path), node)
+            }
+            Instruction::Move {
+                from_path,
+                from_index,
+                to_path,
+                to_index,
+            } => {
+                // The destination is resolved once the node is gone.
+                let mut removed = before.clone();
+                let delete = Instruction::Delete {
+                    path: from_path.clone(),
+                    index: *from_index,
+                    node: None,
+                };
+                // A failed delete leaves the tree as it was.
+                let _ = apply_instruction_mut(&mut removed, &delete);
+                format!(
+                    "Move from {} to {}",
+                    at(before, from_path, *from_index),
+                    at(&removed, to_path, *to_index)
+                )
+            }
+        }
+    }
+
+    /// The instruction that undoes this one, applied to the tree this one
+    /// produces. `None` if the content it removes or overwrites is not
+    /// recorded; see `with_prior`.
+    pub fn invert(&self) -> Option<Instruction> {
+        Some(match self {
+            Instruction::Insert { path, index, node } => Instruction::Delete {
+                path: path.clone(),
+                index: *index,
+                node: Some(Box::new(node.clone())),
+            },
+            Instruction::Delete { path, index, node } => Instruction::Insert {
+                path: path.clone(),
+                index: *index,
+                node: *node.clone()?,
+            },
+            Instruction::Update {
+                path,
+                new_value,
+                old_value,
+            } => Instruction::Update {
+                path: path.clone(),
+                new_value: old_value.clone()?,
+                old_value: Some(new_value.clone()),
+            },
+            Instruction::Replace { path, node, old } => Instruction::Replace {
+                path: path.clone(),
+                node: *old.clone()?,
+                old: Some(Box::new(node.clone())),
+            },
+            // The node sits at `to_path`/`to_index` once moved, and taking it
+            // out again leaves `from_path` as it was.
+            Instruction::Move {
+                from_path,
+                from_index,
+                to_path,
+                to_index,
+            } => Instruction::Move {
+                from_path: to_path.clone(),
+                from_index: *to_index,
+                to_path: from_path.clone(),
+                to_index: *from_index,
+            },
+        })
+    }
+
+    /// The instruction with the content it removes or overwrites in `before`,
+    /// the tree it applies to, recorded where it was missing.
+    pub fn with_prior(&self, before: &Ast) -> Instruction {
+        let mut instruction = self.clone();
+        match &mut instruction {
+            Instruction::Delete { path, index, node } if node.is_none() => {
+                *node = child(before, path, *index).cloned().map(Box::new);
+            }
+            Instruction::Update {
+                path, old_value, ..
+            } if old_value.is_none() => {
+                *old_value = match before.get(path) {
+                    Some(Ast::Atom(value) | Ast::Literal(_, value) | Ast::Str(value)) => {
+                        Some(value.clone())
+                    }
+                    _ => None,
+                };
+            }
+            Instruction::Replace { path, old, .. } if old.is_none() => {
+                *old = before.get(path).cloned().map(Box::new);
+            }
+            _ => {}
         }
+        instruction
+    }
+}
+
+/// The instructions that undo `instructions`, or `None` if one of them
+/// cannot be inverted.
+pub fn invert_all(instructions: &[Instruction]) -> Option<Vec<Instruction>> {
+    instructions.iter().rev().map(Instruction::invert).collect()
+}
+
+fn child<'a>(ast: &'a Ast, path: &[usize], index: usize) -> Option<&'a Ast> {
+    ast.get(path)?.children().get(index)
+}
+
+/// Numbers that only change spelling (`10` → `10.0`) are told apart from
+/// numbers that change value.
+fn describe_literal(old_kind: LiteralKind, old: &str, new_kind: LiteralKind, new: &str) -> String {
+    if old_kind.is_numeric() && new_kind.is_numeric() {
+        let same_value = matches!(
+            (old.parse::<f64>(), new.parse::<f64>()),
+            (Ok(a), Ok(b)) if a == b
+        );
+        let change = if same_value { "reformatted" } else { "changed" };
+        format!("numeric literal {} {} → {}", change, old, new)
+    } else if old_kind == new_kind {
+        format!("{} changed {} → {}", old_kind, old, new)
+    } else {
+        format!("{} {} replaced with {} {}", old_kind, old, new_kind, new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, tokenize};
+
+    #[test]
+    fn test_describe_distinguishes_literals_from_symbols() {
+        let before = parse(&tokenize("(define x 10)")).unwrap();
+        let update = |path: Vec<usize>, value: &str| Instruction::Update {
+            path,
+            new_value: value.to_string(),
+            old_value: None,
+        };
+        assert_eq!(
+            update(vec![0, 2], "20").describe(&before),
+            "numeric literal changed 10 → 20"
+        );
+        assert_eq!(
+            update(vec![0, 1], "y").describe(&before),
+            "symbol renamed x → y"
+        );
+        let reformat = Instruction::Replace {
+            path: vec![0, 2],
+            node: Ast::Literal(LiteralKind::Float, "10.0".to_string()),
+            old: None,
+        };
+        assert_eq!(
+            reformat.describe(&before),
+            "numeric literal reformatted 10 → 10.0"
+        );
+    }
+
+    #[test]
+    fn test_locate_in_names_paths() {
+        let before = parse(&tokenize("(define (f a) (g a))\n(f 1)")).unwrap();
+        let update = Instruction::Update {
+            path: vec![0, 2, 0],
+            new_value: "h".to_string(),
+            old_value: None,
+        };
+        assert_eq!(
+            update.locate_in(&before),
+            "Update at (define f) > body > (g ...) > head with value h"
+        );
+        let insert = Instruction::Insert {
+            path: vec![1],
+            index: 2,
+            node: Ast::Atom("b".to_string()),
+        };
+        assert_eq!(insert.locate_in(&before), "Insert at (f ...) > end node b");
+        let swap = Instruction::Move {
+            from_path: vec![],
+            from_index: 1,
+            to_path: vec![],
+            to_index: 0,
+        };
+        assert_eq!(swap.locate_in(&before), "Move from (f ...) to (define f)");
     }
 }
@@ -0,0 +1,370 @@
+use crate::ast::{Ast, Trivia, escape_string};
+use crate::diff::lcs;
+use crate::parser::Token;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// The concrete syntax an `Ast` leaves out: the whitespace around every token
+/// and the exact spelling of tokens that print differently from how they were
+/// written (`"\u{e9}"` for `"é"`, a blank line holding indentation). Together
+/// with the tree it reproduces the source byte for byte.
+///
+/// The tree already says how tokens nest, so the layout is a flat list with
+/// one entry per token, in the order `tokens` lists them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Layout {
+    pub tokens: Vec<TokenLayout>,
+    /// Whatever follows the last token.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub trailing: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenLayout {
+    /// The whitespace between the previous token and this one.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub before: String,
+    /// How the token was written, if not the way the tree prints it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spelling: Option<String>,
+}
+
+/// The tokens of a file whose top-level forms are the children of `root`, as
+/// the tree prints them.
+pub fn tokens(root: &Ast) -> Vec<String> {
+    let mut tokens = Vec::new();
+    root.children()
+        .iter()
+        .for_each(|form| push_tokens(form, &mut tokens));
+    tokens
+}
+
+fn push_tokens(ast: &Ast, tokens: &mut Vec<String>) {
+    match ast {
+        Ast::Atom(text) | Ast::Literal(_, text) | Ast::Error(text) => tokens.push(text.clone()),
+        Ast::Str(text) => tokens.push(format!("\"{}\"", escape_string(text))),
+        Ast::List(delimiter, items) => {
+            tokens.push(delimiter.open().to_string());
+            items.iter().for_each(|item| push_tokens(item, tokens));
+            if !matches!(items.last(), Some(Ast::Missing(_))) {
+                tokens.push(delimiter.close().to_string());
+            }
+        }
+        Ast::Quoted(reader_macro, form) => {
+            tokens.push(reader_macro.prefix().to_string());
+            push_tokens(form, tokens);
+        }
+        // The parser reads a run of blank lines as one token.
+        Ast::Trivia(Trivia::BlankLine) => tokens.push("\n\n".to_string()),
+        Ast::Trivia(trivia) => tokens.push(trivia.to_string()),
+        Ast::Missing(_) => {}
+    }
+}
+
+impl Layout {
+    /// The layout of `source`, given its tokens and the tree parsed from them.
+    /// `None` if the tree does not have one token per source token.
+    pub fn extract(source: &str, source_tokens: &[Token], root: &Ast) -> Option<Layout> {
+        let printed = tokens(root);
+        if printed.len() != source_tokens.len() {
+            return None;
+        }
+        let mut end = 0;
+        let mut layout = Layout::default();
+        for (token, printed) in source_tokens.iter().zip(printed) {
+            // A blank line token stands for the whole run of whitespace it covers.
+            let written = &source[token.span.start..token.span.end];
+            layout.tokens.push(TokenLayout {
+                before: source[end..token.span.start].to_string(),
+                spelling: (written != printed).then(|| written.to_string()),
+            });
+            end = token.span.end;
+        }
+        layout.trailing = source[end..].to_string();
+        Some(layout)
+    }
+
+    /// The entries of each top-level form of `root`, in order, or `None` if
+    /// this layout belongs to a tree with a different number of tokens.
+    pub fn forms(&self, root: &Ast) -> Option<Vec<&[TokenLayout]>> {
+        if tokens(root).len() != self.tokens.len() {
+            return None;
+        }
+        let mut start = 0;
+        let mut forms = Vec::new();
+        for form in root.children() {
+            let mut form_tokens = Vec::new();
+            push_tokens(form, &mut form_tokens);
+            forms.push(&self.tokens[start..start + form_tokens.len()]);
+            start += form_tokens.len();
+        }
+        Some(forms)
+    }
+
+    /// The source text of `root` laid out like this, or `None` if this layout
+    /// belongs to a tree with a different number of tokens.
+    pub fn render(&self, root: &Ast) -> Option<String> {
+        let printed = tokens(root);
+        if printed.len() != self.tokens.len() {
+            return None;
+        }
+        let mut text = String::new();
+        for (layout, printed) in self.tokens.iter().zip(printed) {
+            text.push_str(&layout.before);
+            text.push_str(layout.spelling.as_deref().unwrap_or(&printed));
+        }
+        text.push_str(&self.trailing);
+        Some(text)
+    }
+}
+
+/// Above this many cells the delta gives up on aligning the changed middle
+/// of two layouts and replaces it wholesale.
+const MAX_ALIGNMENT_CELLS: usize = 1 << 22;
+
+/// One step of a `LayoutDelta`, consuming entries of the old layout.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayoutEdit {
+    /// Keep the next `n` entries.
+    Keep(usize),
+    /// Drop the next `n` entries.
+    Remove(usize),
+    /// Add entries before the next one.
+    Insert(Vec<TokenLayout>),
+}
+
+/// How a commit's layout differs from its parent's. Entries after the last
+/// edit are kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutDelta {
+    pub edits: Vec<LayoutEdit>,
+    /// The new trailing text, if it changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trailing: Option<String>,
+    /// The entries the `Remove` edits drop, in order. Deltas recorded before
+    /// they kept these cannot be inverted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed: Option<Vec<TokenLayout>>,
+    /// The old trailing text, if it changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_trailing: Option<String>,
+}
+
+impl LayoutDelta {
+    /// The delta from `old` to `new`, or `None` if they are the same.
+    pub fn between(old: &Layout, new: &Layout) -> Option<LayoutDelta> {
+        if old == new {
+            return None;
+        }
+        let (n, m) = (old.tokens.len(), new.tokens.len());
+        let prefix = old
+            .tokens
+            .iter()
+            .zip(&new.tokens)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let suffix = old.tokens[prefix..]
+            .iter()
+            .rev()
+            .zip(new.tokens[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let pairs = if (n - prefix - suffix) * (m - prefix - suffix) <= MAX_ALIGNMENT_CELLS {
+            lcs(&old.tokens, &new.tokens)
+        } else {
+            (0..prefix)
+                .map(|k| (k, k))
+                .chain((0..suffix).map(|k| (n - suffix + k, m - suffix + k)))
+                .collect()
+        };
+
+        let mut edits = Vec::new();
+        let mut removed = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        for (oi, nj) in pairs.into_iter().chain([(n, m)]) {
+            if oi > i {
+                edits.push(LayoutEdit::Remove(oi - i));
+                removed.extend_from_slice(&old.tokens[i..oi]);
+            }
+            if nj > j {
+                edits.push(LayoutEdit::Insert(new.tokens[j..nj].to_vec()));
+            }
+            if oi < n {
+                match edits.last_mut() {
+                    Some(LayoutEdit::Keep(kept)) => *kept += 1,
+                    _ => edits.push(LayoutEdit::Keep(1)),
+                }
+            }
+            (i, j) = (oi + 1, nj + 1);
+        }
+        if matches!(edits.last(), Some(LayoutEdit::Keep(_))) {
+            edits.pop();
+        }
+        let trailing_changed = old.trailing != new.trailing;
+        Some(LayoutDelta {
+            edits,
+            trailing: trailing_changed.then(|| new.trailing.clone()),
+            removed: (!removed.is_empty()).then_some(removed),
+            old_trailing: trailing_changed.then(|| old.trailing.clone()),
+        })
+    }
+
+    /// The delta that undoes this one, applied to the layout it produces, or
+    /// `None` if what it removes or overwrites was not recorded.
+    pub fn invert(&self) -> Option<LayoutDelta> {
+        let removed = self.removed.as_deref().unwrap_or_default();
+        let mut next = 0;
+        let mut inserted = Vec::new();
+        let mut edits = Vec::new();
+        for edit in &self.edits {
+            edits.push(match edit {
+                LayoutEdit::Keep(n) => LayoutEdit::Keep(*n),
+                LayoutEdit::Remove(n) => {
+                    let entries = removed.get(next..next + n)?.to_vec();
+                    next += n;
+                    LayoutEdit::Insert(entries)
+                }
+                LayoutEdit::Insert(entries) => {
+                    inserted.extend_from_slice(entries);
+                    LayoutEdit::Remove(entries.len())
+                }
+            });
+        }
+        if self.trailing.is_some() && self.old_trailing.is_none() {
+            return None;
+        }
+        Some(LayoutDelta {
+            edits,
+            trailing: self.old_trailing.clone(),
+            removed: (!inserted.is_empty()).then_some(inserted),
+            old_trailing: self.trailing.clone(),
+        })
+    }
+
+    /// Applies the delta to `base`, or `None` if it removes or keeps more
+    /// entries than `base` has.
+    pub fn apply(&self, base: &Layout) -> Option<Layout> {
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        for edit in &self.edits {
+            match edit {
+                LayoutEdit::Keep(n) => {
+                    tokens.extend_from_slice(base.tokens.get(i..i + n)?);
+                    i += n;
+                }
+                LayoutEdit::Remove(n) => {
+                    base.tokens.get(i..i + n)?;
+                    i += n;
+                }
+                LayoutEdit::Insert(entries) => tokens.extend_from_slice(entries),
+            }
+        }
+        tokens.extend_from_slice(base.tokens.get(i..)?);
+        Some(Layout {
+            tokens,
+            trailing: self
+                .trailing
+                .clone()
+                .unwrap_or_else(|| base.trailing.clone()),
+        })
+    }
+}
+
+impl Display for LayoutDelta {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut parts: Vec<String> = self
+            .edits
+            .iter()
+            .map(|edit| match edit {
+                LayoutEdit::Keep(n) => format!("keep {}", n),
+                LayoutEdit::Remove(n) => format!("remove {}", n),
+                LayoutEdit::Insert(entries) => format!("insert {}", entries.len()),
+            })
+            .collect();
+        if self.trailing.is_some() {
+            parts.push("change trailing whitespace".to_string());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_recovering, tokenize};
+    use proptest::prelude::*;
+
+    fn layout_of(source: &str) -> (Ast, Layout) {
+        let tokens = tokenize(source);
+        let (ast, _) = parse_recovering(&tokens);
+        let layout = Layout::extract(source, &tokens, &ast).unwrap();
+        (ast, layout)
+    }
+
+    #[test]
+    fn test_layout_reproduces_source() {
+        let sources = [
+            "",
+            "  \n",
+            "(define  x\t10)\r\n\r\n\n   ;; note\r\n(f \"\\u{e9}\" 'y\n   [1 2] {:a #\\b})  \n",
+            "#| block\n |#\n\n  (a\n\n\n   b)\n(let [x 1)\n\"open",
+        ];
+        for source in sources {
+            let (ast, layout) = layout_of(source);
+            assert_eq!(layout.render(&ast).as_deref(), Some(source));
+        }
+        let (ast, layout) = layout_of("(a b)");
+        assert_eq!(layout.render(&Ast::list(vec![])), None);
+        assert_eq!(layout.tokens[2].before, " ");
+        assert_eq!(layout.render(&ast).unwrap(), "(a b)");
+    }
+
+    #[test]
+    fn test_layout_delta_only_stores_changes() {
+        let (_, old) = layout_of("(a b)\n(c d)\n(e f)\n");
+        let (_, new) = layout_of("(a b)\n(c   d)\n(e f)");
+        let delta = LayoutDelta::between(&old, &new).unwrap();
+        assert_eq!(
+            delta.to_string(),
+            "keep 6, remove 1, insert 1, change trailing whitespace"
+        );
+        assert_eq!(delta.apply(&old), Some(new.clone()));
+        assert_eq!(LayoutDelta::between(&new, &new), None);
+        assert_eq!(delta.apply(&Layout::default()), None);
+
+        // Deltas from before removed entries were recorded cannot be undone.
+        let unrecorded = LayoutDelta {
+            removed: None,
+            ..delta
+        };
+        assert_eq!(unrecorded.invert(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn layout_delta_roundtrip(
+            old in prop::collection::vec("[ \n]{0,2}", 0..12),
+            new in prop::collection::vec("[ \n]{0,2}", 0..12),
+        ) {
+            let layout = |gaps: Vec<String>| Layout {
+                tokens: gaps
+                    .into_iter()
+                    .map(|before| TokenLayout { before, spelling: None })
+                    .collect(),
+                trailing: String::new(),
+            };
+            let (old, new) = (layout(old), layout(new));
+            let delta = LayoutDelta::between(&old, &new);
+            let applied = match &delta {
+                Some(delta) => delta.apply(&old),
+                None => Some(old.clone()),
+            };
+            prop_assert_eq!(applied, Some(new.clone()));
+            if let Some(delta) = delta {
+                let inverse = delta.invert().unwrap();
+                prop_assert_eq!(inverse.apply(&new), Some(old));
+                prop_assert_eq!(inverse.invert(), Some(delta));
+            }
+        }
+    }
+}
@@ -1,6 +1,20 @@
 pub mod apply;
 pub mod ast;
+pub mod config;
+pub mod dag;
 pub mod diff;
 pub mod instruction;
+pub mod layout;
+pub mod locator;
+pub mod merge;
+pub mod migrate;
 pub mod parser;
+pub mod pretty;
+pub mod refs;
+pub mod render;
+pub mod transform;
 pub mod vc;
+pub mod workdir;
+
+#[cfg(test)]
+mod testutil;
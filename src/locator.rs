@@ -0,0 +1,345 @@
+use crate::ast::{Ast, Trivia};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// Forms whose trailing items are a body of forms, with the index of the
+/// first one.
+const BODY_FORMS: &[(&str, usize)] = &[
+    ("begin", 1),
+    ("define", 2),
+    ("defmacro", 3),
+    ("defun", 3),
+    ("lambda", 2),
+    ("let", 2),
+    ("let*", 2),
+    ("letrec", 2),
+    ("progn", 1),
+    ("unless", 2),
+    ("when", 2),
+];
+
+/// Labels longer than this are cut short.
+const MAX_LABEL: usize = 30;
+
+/// Why a locator does not name a place in a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocatorError {
+    /// A segment that is neither a label nor a step.
+    UnknownSegment(String),
+    /// A segment that names nothing at its place in the tree.
+    NotFound(String),
+}
+
+impl Display for LocatorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocatorError::UnknownSegment(segment) => write!(
+                f,
+                "'{}' is not a locator step; expected 'body', 'head', 'arg N', 'item N', 'quoted' or 'end'",
+                segment
+            ),
+            LocatorError::NotFound(segment) => write!(f, "nothing matches '{}'", segment),
+        }
+    }
+}
+
+impl Error for LocatorError {}
+
+/// The locator of `path` in `root`, such as
+/// `(define fib) > body > (if ...) > arg 2`. The last index may be one past
+/// the end of its list, as in the path of an insertion. Paths that do not fit
+/// the tree are shown as numbers.
+///
+/// Top-level forms and the forms in a body are named by a label such as
+/// `(define fib)`, `(if ...)`, `comment` or the text of a leaf, followed by
+/// ` #N` when several of them share it. The other steps are `body` (into the
+/// body of a form such as `define`, `lambda` or `let`), `head`, `arg N` (the
+/// item at index N of a call), `item N` (the Nth item of any other list),
+/// `quoted` (the form after `'` and the other reader macros) and `end` (the
+/// position after the last item). A ` >` in a label, as in a string, is
+/// written ` \>` so that it cannot be read as a separator.
+pub fn locate(root: &Ast, path: &[usize]) -> String {
+    if path.is_empty() {
+        return "top level".to_string();
+    }
+    let mut segments = Vec::new();
+    let mut node = Some(root);
+    for (depth, &index) in path.iter().enumerate() {
+        let Some(parent) = node else {
+            return format!("{:?}", path);
+        };
+        let items = parent.children();
+        match parent {
+            _ if depth == 0 => segments.push(member(items, 0, index)),
+            Ast::Quoted(..) if index == 0 => segments.push("quoted".to_string()),
+            Ast::List(..) => match (body_start(parent), items.first()) {
+                (Some(start), _) if index >= start => {
+                    segments.push("body".to_string());
+                    segments.push(member(items, start, index));
+                }
+                _ if index >= items.len() => segments.push("end".to_string()),
+                (_, Some(Ast::Atom(_))) if index == 0 => segments.push("head".to_string()),
+                (_, Some(Ast::Atom(_))) => segments.push(format!("arg {}", index)),
+                _ => segments.push(format!("item {}", index + 1)),
+            },
+            _ => return format!("{:?}", path),
+        }
+        if index > items.len() {
+            return format!("{:?}", path);
+        }
+        node = items.get(index);
+    }
+    segments.join(" > ")
+}
+
+/// The path `locator` names in `root`; the inverse of `locate`.
+pub fn resolve(root: &Ast, locator: &str) -> Result<Vec<usize>, LocatorError> {
+    let locator = locator.trim();
+    if locator.is_empty() || locator == "top level" {
+        return Ok(Vec::new());
+    }
+    let mut segments = locator.split(" > ").map(str::trim);
+    let mut path = Vec::new();
+    let mut node = root;
+    let mut start = Some(0);
+    while let Some(segment) = segments.next() {
+        let not_found = || LocatorError::NotFound(segment.to_string());
+        let items = node.children();
+        let index = match start.take() {
+            // A form among the top-level forms or in a body.
+            Some(start) => match find_member(items, start, segment) {
+                Some(index) => index,
+                None if segment == "end" => items.len(),
+                None => return Err(not_found()),
+            },
+            None => match segment {
+                "body" => {
+                    start = Some(body_start(node).ok_or_else(not_found)?);
+                    continue;
+                }
+                "head" if matches!(node, Ast::List(..)) => 0,
+                "quoted" if matches!(node, Ast::Quoted(..)) => 0,
+                "end" if matches!(node, Ast::List(..)) => items.len(),
+                _ => {
+                    let number = |prefix| {
+                        segment
+                            .strip_prefix(prefix)
+                            .and_then(|n: &str| n.trim().parse::<usize>().ok())
+                    };
+                    match (number("arg "), number("item ")) {
+                        (Some(n), _) => n,
+                        (_, Some(n)) if n > 0 => n - 1,
+                        _ if ["head", "quoted", "end"].contains(&segment) => {
+                            return Err(not_found());
+                        }
+                        _ => return Err(LocatorError::UnknownSegment(segment.to_string())),
+                    }
+                }
+            },
+        };
+        path.push(index);
+        match items.get(index) {
+            Some(child) => node = child,
+            // Only the last segment may point past the end.
+            None if index == items.len() && segments.clone().next().is_none() => break,
+            None => return Err(not_found()),
+        }
+    }
+    if start.is_some() {
+        // A trailing `body` names no form in it.
+        return Err(LocatorError::NotFound("body".to_string()));
+    }
+    Ok(path)
+}
+
+/// The segment naming `items[index]` among `items[start..]`.
+fn member(items: &[Ast], start: usize, index: usize) -> String {
+    let Some(item) = items.get(index) else {
+        return "end".to_string();
+    };
+    let name = label(item);
+    let same = |other: &Ast| label(other) == name;
+    if items[start..].iter().filter(|other| same(other)).count() == 1 {
+        name
+    } else {
+        let nth = items[start..index]
+            .iter()
+            .filter(|other| same(other))
+            .count()
+            + 1;
+        format!("{} #{}", name, nth)
+    }
+}
+
+/// The index of the form `segment` names among `items[start..]`.
+fn find_member(items: &[Ast], start: usize, segment: &str) -> Option<usize> {
+    let numbered = segment
+        .rsplit_once(" #")
+        .and_then(|(name, nth)| Some((name, nth.parse().ok().filter(|&nth| nth > 0)?)));
+    let (name, nth) = numbered.unwrap_or((segment, 1));
+    (start..items.len())
+        .filter(|&i| label(&items[i]) == name)
+        .nth(nth - 1)
+}
+
+/// How a form is named in a locator: `(define fib)` for a definition,
+/// `(if ...)` for other calls, `[...]` for data, the text of a leaf.
+fn label(ast: &Ast) -> String {
+    let text = match ast {
+        Ast::List(delimiter, items) => {
+            let (open, close) = (delimiter.open(), delimiter.close());
+            match items.first() {
+                Some(Ast::Atom(head)) => match items.get(1).and_then(name) {
+                    Some(name) if head.starts_with("def") => {
+                        format!("{}{} {}{}", open, head, name, close)
+                    }
+                    _ if items.len() > 1 => format!("{}{} ...{}", open, head, close),
+                    _ => format!("{}{}{}", open, head, close),
+                },
+                Some(_) => format!("{}...{}", open, close),
+                None => format!("{}{}", open, close),
+            }
+        }
+        Ast::Quoted(reader_macro, form) => format!("{}{}", reader_macro.prefix(), label(form)),
+        Ast::Trivia(Trivia::BlankLine) => "blank line".to_string(),
+        Ast::Trivia(_) => "comment".to_string(),
+        Ast::Error(_) => "error".to_string(),
+        Ast::Missing(_) => "missing".to_string(),
+        _ => ast.to_string(),
+    };
+    let text = text.replace(" >", " \\>");
+    match text.char_indices().nth(MAX_LABEL) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// The name a definition defines: `x` in `(define x 1)`, `f` in
+/// `(define (f a) ...)`.
+fn name(item: &Ast) -> Option<&str> {
+    match item {
+        Ast::Atom(name) => Some(name),
+        Ast::List(_, items) => match items.first() {
+            Some(Ast::Atom(name)) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The index of the first body form of `ast`, if it has a body.
+fn body_start(ast: &Ast) -> Option<usize> {
+    let Some(Ast::Atom(head)) = ast.children().first() else {
+        return None;
+    };
+    if !matches!(ast, Ast::List(..)) {
+        return None;
+    }
+    BODY_FORMS
+        .iter()
+        .find(|(form, _)| form == head)
+        .map(|&(_, start)| start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, tokenize};
+    use proptest::prelude::*;
+
+    const FIB: &str = "(define (fib n)\n  (if (< n 2)\n      n\n      (+ (fib (- n 1)) (fib (- n 2)))))\n(display (fib 10))\n(display \"done\")";
+
+    #[test]
+    fn test_locate_names_forms_and_roles() {
+        let root = parse(&tokenize(FIB)).unwrap();
+        assert_eq!(
+            locate(&root, &[0, 2, 2]),
+            "(define fib) > body > (if ...) > arg 2"
+        );
+        assert_eq!(locate(&root, &[0, 1, 0]), "(define fib) > arg 1 > head");
+        assert_eq!(
+            locate(&root, &[1, 1, 1]),
+            "(display ...) #1 > arg 1 > arg 1"
+        );
+        assert_eq!(locate(&root, &[3]), "end");
+        assert_eq!(locate(&root, &[0, 3]), "(define fib) > body > end");
+        assert_eq!(locate(&root, &[]), "top level");
+        assert_eq!(locate(&root, &[0, 9, 9]), "[0, 9, 9]");
+    }
+
+    #[test]
+    fn test_resolve_reads_locators_back() {
+        let root = parse(&tokenize(FIB)).unwrap();
+        assert_eq!(
+            resolve(&root, "(define fib) > body > (if ...) > arg 2"),
+            Ok(vec![0, 2, 2])
+        );
+        assert_eq!(resolve(&root, "(display ...) #2"), Ok(vec![2]));
+        assert_eq!(resolve(&root, "(display ...)"), Ok(vec![1]));
+        assert_eq!(
+            resolve(&root, "(define fib) > nowhere"),
+            Err(LocatorError::UnknownSegment("nowhere".to_string()))
+        );
+        assert_eq!(
+            resolve(&root, "(define fib) > arg 7"),
+            Err(LocatorError::NotFound("arg 7".to_string()))
+        );
+        assert_eq!(
+            resolve(&root, "(display ...) > body"),
+            Err(LocatorError::NotFound("body".to_string()))
+        );
+
+        // A `>` in a string must not split the locator.
+        let root = parse(&tokenize("(defun pos (x) \"True when x > 0.\" (> x 0))")).unwrap();
+        let locator = locate(&root, &[0, 3]);
+        assert_eq!(locator, "(defun pos) > body > \"True when x \\> 0.\"");
+        assert_eq!(resolve(&root, &locator), Ok(vec![0, 3]));
+    }
+
+    fn form_strategy() -> impl Strategy<Value = Ast> {
+        let leaf = prop_oneof![
+            prop::sample::select(vec!["a", "b", "define", "let", "if", "defun"])
+                .prop_map(|atom| Ast::Atom(atom.to_string())),
+            Just(Ast::Trivia(Trivia::LineComment(" note".to_string()))),
+            prop::sample::select(vec!["x > 0", "a > > b", "> ", " >", "> > >"])
+                .prop_map(|text| Ast::Str(text.to_string())),
+        ];
+        leaf.prop_recursive(4, 24, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..5).prop_map(Ast::list),
+                inner.prop_map(|form| parse(&tokenize(&format!("'{}", form)))
+                    .map(|root| root.children()[0].clone())
+                    .unwrap_or(form)),
+            ]
+        })
+    }
+
+    /// Every path to a node in `ast`, and every position one past the end of
+    /// a list.
+    fn paths(ast: &Ast, path: &mut Vec<usize>, out: &mut Vec<Vec<usize>>) {
+        for (i, child) in ast.children().iter().enumerate() {
+            path.push(i);
+            out.push(path.clone());
+            paths(child, path, out);
+            path.pop();
+        }
+        if matches!(ast, Ast::List(..)) {
+            path.push(ast.children().len());
+            out.push(path.clone());
+            path.pop();
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn locators_roundtrip(forms in prop::collection::vec(form_strategy(), 0..4)) {
+            let root = Ast::list(forms);
+            let mut all = Vec::new();
+            paths(&root, &mut vec![], &mut all);
+            for path in all {
+                let locator = locate(&root, &path);
+                prop_assert_eq!(resolve(&root, &locator), Ok(path), "{}", locator);
+            }
+        }
+    }
+}
@@ -1,12 +1,30 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::io::IsTerminal;
 use std::path::Path;
+use trefoil::apply::apply_instruction_mut;
 use trefoil::ast::Ast;
-use trefoil::diff::diff_ast;
-use trefoil::parser::{parse, tokenize};
-use trefoil::vc::Commit;
+use trefoil::config::{Config, repository_config_path, user_config_path};
+use trefoil::dag::{CommitGraph, render_graph};
+use trefoil::diff::{DiffAlgorithm, diff_ast};
+use trefoil::locator::resolve;
+use trefoil::merge::merge_trees;
+use trefoil::migrate::{is_legacy_repository, migrate_repository};
+use trefoil::pretty::{PrettyConfig, pretty};
+use trefoil::refs::{
+    DEFAULT_BRANCH, Head, branch_exists, delete_branch, list_branches, read_branch, read_head,
+    write_branch, write_head,
+};
+use trefoil::render::{Highlight, hunks};
+use trefoil::vc::{Commit, CommitMetadata, FileChange, Tree};
 use trefoil::vc::{
-    get_commit_chain, get_current_commit_id, reconstruct_ast, save_commit, set_current_commit_id,
+    diff_trees, get_current_commit_id, load_commit, prune_snapshots, reconstruct, replay_cost,
+    resolve_commit_id, save_commit, save_manual_snapshot, save_snapshot, set_current_commit_id,
+    short_id, undo_changes,
+};
+use trefoil::workdir::{
+    FileStatus, has_changes, list_files, read_file, read_tree, render_file, status, write_tree,
 };
 
 #[derive(Parser)]
@@ -14,140 +32,768 @@ use trefoil::vc::{
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Whether to highlight changes with colours or with {+ +} and [- -] markers.
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Init,
-    Commit,
-    Log,
-    Checkout { id: u64 },
-    Debug { id: u64 },
+    Commit {
+        /// Commit message. Without it, $VISUAL or $EDITOR is opened to write one.
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Diff strategy used to compute the stored instructions.
+        #[arg(long, value_enum, default_value_t = DiffAlgorithm::Fast)]
+        algorithm: DiffAlgorithm,
+        /// Commit even if some files do not parse, keeping the unreadable
+        /// parts as error nodes.
+        #[arg(long)]
+        allow_errors: bool,
+    },
+    Log {
+        /// Draw the branch and merge structure next to the history.
+        #[arg(long)]
+        graph: bool,
+        /// Show the changes each commit made to its first parent.
+        #[arg(short = 'p', long)]
+        patch: bool,
+    },
+    /// Show how the files in the working directory differ from HEAD.
+    Status {
+        /// One `<status> <path>` line per file, for scripts and editors.
+        #[arg(long)]
+        porcelain: bool,
+    },
+    /// Show what changed between two commits, or from a commit (HEAD by
+    /// default) to the working directory.
+    Diff {
+        from: Option<String>,
+        to: Option<String>,
+    },
+    /// Show a commit and its changes, or a file as it is in the commit.
+    Show {
+        id: String,
+        path: Option<String>,
+        /// Show only the form at this locator in the file, such as
+        /// "(define fib) > body > (if ...)".
+        #[arg(requires = "path")]
+        locator: Option<String>,
+    },
+    /// Check out a commit (or a unique prefix of its id) with a detached HEAD.
+    Checkout {
+        id: String,
+    },
+    /// List branches, create one at HEAD, or delete one with -d.
+    Branch {
+        name: Option<String>,
+        #[arg(short = 'd', long = "delete", requires = "name")]
+        delete: bool,
+    },
+    /// Check out a branch and make it current.
+    Switch {
+        name: String,
+    },
+    /// Merge a branch into the current one.
+    Merge {
+        branch: String,
+        /// Message for the merge commit, instead of "Merge branch '<branch>'".
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// Make a commit that undoes the changes of an earlier one.
+    Revert {
+        id: String,
+        /// Message for the new commit, instead of "Revert \"<subject>\"".
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    Debug {
+        id: String,
+    },
+    /// Store the full AST of a commit (HEAD by default) to speed up checkout.
+    Snapshot {
+        id: Option<String>,
+    },
+    Gc {
+        /// Remove snapshots that are no longer needed: those of commits that
+        /// are gone and those not yet due. Ones made with `snapshot` are kept.
+        #[arg(long)]
+        snapshots: bool,
+    },
+    /// Convert a repository with integer commit ids to content-addressed ids.
+    Migrate,
+    /// Reformat tracked files with the pretty printer.
+    Fmt {
+        /// Files to format, instead of every tracked one.
+        paths: Vec<String>,
+        /// Fail instead of writing if any file is not formatted.
+        #[arg(long)]
+        check: bool,
+        /// Line width to format to, instead of format.width.
+        #[arg(long)]
+        width: Option<usize>,
+    },
+    /// Show or set a configuration value such as user.name or user.email.
+    Config {
+        key: String,
+        value: Option<String>,
+        /// Use the user config instead of the repository's.
+        #[arg(long)]
+        global: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    /// Colours when writing to a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+fn main() {
+    if let Err(err) = run(Cli::parse()) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
     let vcdir = Path::new(".trefoil");
     let commits_dir = vcdir.join("commits");
+    let snapshots_dir = vcdir.join("snapshots");
+    let root = Path::new(".");
+    let colors = match cli.color {
+        ColorChoice::Auto => std::io::stdout().is_terminal(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    if !matches!(cli.command, Commands::Init | Commands::Migrate)
+        && vcdir.exists()
+        && is_legacy_repository(vcdir)?
+    {
+        return Err(
+            "This repository uses integer commit ids. Run 'trefoil migrate' to convert it.".into(),
+        );
+    }
     match cli.command {
         Commands::Init => {
             if vcdir.exists() {
                 println!("Repository already initialized.");
             } else {
                 std::fs::create_dir_all(&commits_dir)?;
-                let root_commit = Commit {
-                    id: 0,
-                    parent_id: None,
-                    instructions: vec![],
-                    timestamp: 0, // TODO: use real timestamp
+                let config = Config::load_all(vcdir)?;
+                let metadata = CommitMetadata {
+                    author: config.author().ok(),
+                    committer: config.committer().ok(),
+                    ..CommitMetadata::now("Initial commit".to_string())
                 };
+                let root_commit = Commit::new(vec![], vec![], metadata).with_files(BTreeMap::new());
                 save_commit(&root_commit, &commits_dir)?;
-                set_current_commit_id(0, vcdir)?;
-
-                let code_file = Path::new("code.lisp");
-                if !code_file.exists() {
-                    std::fs::write(code_file, "")?;
-                    println!("Initialized empty repository and created empty 'code.lisp'.");
-                } else {
-                    println!("Initialized empty repository. 'code.lisp' already exists.");
-                }
+                write_branch(DEFAULT_BRANCH, &root_commit.id, vcdir)?;
+                write_head(&Head::Branch(DEFAULT_BRANCH.to_string()), vcdir)?;
+                println!("Initialized empty repository.");
             }
         }
-        Commands::Commit => {
-            let code = std::fs::read_to_string("code.lisp")?;
-            let tokens = tokenize(&code);
-            let new_ast = parse(&tokens)?;
+        Commands::Commit {
+            message,
+            algorithm,
+            allow_errors,
+        } => {
+            let (new_tree, diagnostics) = read_tree(root)?;
+            if !diagnostics.is_empty() {
+                if !allow_errors {
+                    return Err(format!(
+                        "{}\n\nFix the code above, or commit it as it is with --allow-errors.",
+                        diagnostics.join("\n\n")
+                    )
+                    .into());
+                }
+                for diagnostic in &diagnostics {
+                    eprintln!("Warning: {}\n", diagnostic);
+                }
+            }
 
             let current_id = get_current_commit_id(vcdir)?;
-            let current_ast = reconstruct_ast(current_id, &commits_dir)?;
-
-            let mut path = vec![];
-            let instructions = diff_ast(&current_ast, &new_ast, &mut path);
+            let current_tree = reconstruct(&current_id, &commits_dir, &snapshots_dir)?;
+            let changes = diff_trees(&current_tree, &new_tree, algorithm);
 
-            if instructions.is_empty() {
-                println!("No changes detected in 'code.lisp'. Nothing to commit.");
+            if changes.is_empty() {
+                println!("No changes detected. Nothing to commit.");
             } else {
-                let commit_files = std::fs::read_dir(&commits_dir)?
-                    .filter_map(|entry| entry.ok())
-                    .filter_map(|entry| entry.path().file_stem()?.to_str()?.parse::<u64>().ok())
-                    .collect::<Vec<u64>>();
-                let next_id = commit_files.iter().max().map_or(0, |max_id| max_id + 1);
-
-                let new_commit = Commit {
-                    id: next_id,
-                    parent_id: Some(current_id),
-                    instructions,
-                    timestamp: 0, // TODO: use real timestamp
+                let config = Config::load_all(vcdir)?;
+                let (author, committer) = (config.author()?, config.committer()?);
+                let message = match message {
+                    Some(message) => message,
+                    None => edit_message(vcdir)?,
                 };
+                let metadata = CommitMetadata {
+                    author: Some(author),
+                    committer: Some(committer),
+                    ..CommitMetadata::now(message)
+                };
+                let formatting_only = changes.values().all(|change| {
+                    matches!(change, FileChange::Modified(edit) if edit.instructions.is_empty())
+                });
+                let new_commit = Commit::new(vec![current_id], vec![], metadata)
+                    .with_files(changes)
+                    .with_removed_files(&current_tree);
+                let new_id = &new_commit.id;
                 save_commit(&new_commit, &commits_dir)?;
-                set_current_commit_id(next_id, vcdir)?;
-                println!("Committed changes as commit {}", next_id);
+                set_current_commit_id(new_id, vcdir)?;
+                if formatting_only {
+                    println!(
+                        "Committed formatting changes as commit {}",
+                        short_id(new_id)
+                    );
+                } else {
+                    println!("Committed changes as commit {}", short_id(new_id));
+                }
+
+                if replay_cost(new_id, &commits_dir, &snapshots_dir)?.needs_snapshot() {
+                    save_snapshot(new_id, &new_tree, &snapshots_dir)?;
+                    println!("Saved snapshot for commit {}", short_id(new_id));
+                }
             }
         }
-        Commands::Log => {
+        Commands::Log {
+            graph: draw_graph,
+            patch,
+        } => {
             let current_id = get_current_commit_id(vcdir)?;
-            let chain = get_commit_chain(current_id, &commits_dir)?;
-            if chain.is_empty() {
+            let head = read_head(vcdir)?;
+            let branches = list_branches(vcdir)?;
+            let graph = CommitGraph::load(&[&current_id], &commits_dir)?;
+            let order = graph.topological_order(&[&current_id]);
+            let rows = if draw_graph {
+                render_graph(&graph, &order)
+            } else {
+                order
+                    .iter()
+                    .map(|id| ("*".to_string(), Some(id.clone())))
+                    .collect()
+            };
+            if order.is_empty() {
                 println!("No commits found.");
             } else {
                 println!("Commit History (newest first):");
-                for commit in &chain {
-                    print!(
-                        "* commit {} (parent: {:?})",
-                        commit.id,
-                        commit
-                            .parent_id
-                            .map(|id| id.to_string())
-                            .unwrap_or_else(|| "None".to_string())
-                    );
+                for (art, id) in rows {
+                    let Some(commit) = id.and_then(|id| graph.get(&id)) else {
+                        println!("{}", art);
+                        continue;
+                    };
+                    print!("{} commit {}", art, commit.id);
+                    match commit.parents.as_slice() {
+                        [] => print!(" (parent: None)"),
+                        [parent] => print!(" (parent: {})", short_id(parent)),
+                        parents => print!(
+                            " (parents: {})",
+                            parents
+                                .iter()
+                                .map(|id| short_id(id))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    }
+                    let mut decorations = Vec::new();
                     if commit.id == current_id {
-                        print!(" (HEAD)");
+                        decorations.push(match &head {
+                            Head::Branch(name) => format!("HEAD -> {}", name),
+                            Head::Detached(_) => "HEAD".to_string(),
+                        });
+                    }
+                    for (name, id) in &branches {
+                        if *id == commit.id && head != Head::Branch(name.clone()) {
+                            decorations.push(name.clone());
+                        }
+                    }
+                    if !decorations.is_empty() {
+                        print!(" ({})", decorations.join(", "));
                     }
                     println!();
+
+                    // Detail lines continue the lanes, ending the commit's own lane at a root.
+                    let rail = art.replace('*', if commit.parents.is_empty() { " " } else { "|" });
+                    print_details(commit, &format!("{} ", rail));
+                    if patch {
+                        let diff = commit_diff(commit, vcdir, colors)?;
+                        if !diff.is_empty() {
+                            println!("{}", rail.trim_end());
+                        }
+                        for line in diff.lines() {
+                            println!("{}", format!("{} {}", rail, line).trim_end());
+                        }
+                    }
+                    println!("{}", rail.trim_end());
                 }
             }
         }
+        Commands::Status { porcelain } => {
+            let head_id = get_current_commit_id(vcdir)?;
+            let head = reconstruct(&head_id, &commits_dir, &snapshots_dir)?;
+            let statuses = status(root, &head)?;
+            if porcelain {
+                for (path, status) in &statuses {
+                    println!("{} {}", status, path);
+                }
+                return Ok(());
+            }
+            match read_head(vcdir)? {
+                Head::Branch(name) => {
+                    println!("On branch {} (commit {}).", name, short_id(&head_id))
+                }
+                Head::Detached(_) => println!("HEAD detached at commit {}.", short_id(&head_id)),
+            }
+            let mut clean = 0;
+            for (path, status) in &statuses {
+                let detail = match status {
+                    FileStatus::Clean => {
+                        clean += 1;
+                        continue;
+                    }
+                    FileStatus::Modified(changes) => format!(" ({} change(s))", changes),
+                    FileStatus::Reformatted => " (formatting only)".to_string(),
+                    FileStatus::Unparsable(errors) => format!(" ({} parse error(s))", errors),
+                    FileStatus::Deleted | FileStatus::Untracked => String::new(),
+                };
+                println!("  {:<12} {}{}", format!("{}:", status), path, detail);
+            }
+            if clean == statuses.len() {
+                println!("Nothing to commit; the working directory matches HEAD.");
+            } else if clean > 0 {
+                println!("{} other file(s) unchanged.", clean);
+            }
+        }
+        Commands::Diff { from, to } => {
+            let from = match from {
+                Some(id) => resolve_commit_id(&id, &commits_dir)?,
+                None => get_current_commit_id(vcdir)?,
+            };
+            let old = reconstruct(&from, &commits_dir, &snapshots_dir)?;
+            let new = match to {
+                Some(id) => {
+                    let id = resolve_commit_id(&id, &commits_dir)?;
+                    reconstruct(&id, &commits_dir, &snapshots_dir)?
+                }
+                None => {
+                    let (tree, diagnostics) = read_tree(root)?;
+                    for diagnostic in &diagnostics {
+                        eprintln!("Warning: {}\n", diagnostic);
+                    }
+                    tree
+                }
+            };
+            let output = diff_text(&old, &new, &pretty_config(vcdir)?, colors);
+            if output.is_empty() {
+                println!("No changes.");
+            }
+            print!("{}", output);
+        }
+        Commands::Show { id, path, locator } => {
+            let id = resolve_commit_id(&id, &commits_dir)?;
+            let commit = load_commit(&id, &commits_dir)?;
+            let Some(path) = path else {
+                println!("commit {}", commit.id);
+                print_details(&commit, "");
+                let diff = commit_diff(&commit, vcdir, colors)?;
+                if !diff.is_empty() {
+                    println!();
+                }
+                print!("{}", diff);
+                return Ok(());
+            };
+            let tree = reconstruct(&id, &commits_dir, &snapshots_dir)?;
+            let Some(file) = tree.get(&path) else {
+                return Err(format!("'{}' is not in commit {}.", path, short_id(&id)).into());
+            };
+            let config = pretty_config(vcdir)?;
+            match locator {
+                Some(locator) => {
+                    let located = resolve(&file.ast, &locator).map_err(|err| {
+                        format!("Cannot find '{}' in {}: {}.", locator, path, err)
+                    })?;
+                    let Some(node) = file.ast.get(&located).filter(|_| !located.is_empty()) else {
+                        return Err(format!("'{}' names no form in {}.", locator, path).into());
+                    };
+                    print!("{}", pretty(&Ast::list(vec![node.clone()]), &config));
+                }
+                None => print!("{}", render_file(file, &config).0),
+            }
+        }
         Commands::Checkout { id } => {
-            let commit_path = commits_dir.join(format!("{}.json", id));
-            if !commit_path.exists() {
-                return Err(format!("Commit with id '{}' not found.", id).into());
+            let id = resolve_commit_id(&id, &commits_dir)?;
+            check_out(root, vcdir, &id)?;
+            write_head(&Head::Detached(id.clone()), vcdir)?;
+            println!(
+                "Checked out commit {}. Working directory updated.",
+                short_id(&id)
+            );
+        }
+        Commands::Branch { name, delete } => match name {
+            None => {
+                let head = read_head(vcdir)?;
+                for (branch, id) in list_branches(vcdir)? {
+                    let marker = if head == Head::Branch(branch.clone()) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    println!("{} {} (commit {})", marker, branch, short_id(&id));
+                }
+                if let Head::Detached(id) = head {
+                    println!("* (HEAD detached at commit {})", short_id(&id));
+                }
             }
+            Some(name) if delete => {
+                if read_head(vcdir)? == Head::Branch(name.clone()) {
+                    return Err(format!("Cannot delete the current branch '{}'.", name).into());
+                }
+                let id = read_branch(&name, vcdir)?;
+                delete_branch(&name, vcdir)?;
+                println!("Deleted branch {} (was commit {}).", name, short_id(&id));
+            }
+            Some(name) => {
+                if branch_exists(&name, vcdir) {
+                    return Err(format!("Branch '{}' already exists.", name).into());
+                }
+                let id = get_current_commit_id(vcdir)?;
+                write_branch(&name, &id, vcdir)?;
+                println!("Created branch {} at commit {}.", name, short_id(&id));
+            }
+        },
+        Commands::Switch { name } => {
+            let id = read_branch(&name, vcdir)?;
+            check_out(root, vcdir, &id)?;
+            write_head(&Head::Branch(name.clone()), vcdir)?;
+            println!(
+                "Switched to branch {} (commit {}). Working directory updated.",
+                name,
+                short_id(&id)
+            );
+        }
+        Commands::Debug { id } => {
+            let id = resolve_commit_id(&id, &commits_dir)?;
+            let commit = load_commit(&id, &commits_dir)?;
 
-            let ast = reconstruct_ast(id, &commits_dir)?;
-
-            let code_to_write = match ast {
-                Ast::List(nodes) => nodes
+            println!(
+                "Instructions stored IN commit {}: (Transforming from parents [{}] to {})",
+                short_id(&id),
+                commit
+                    .parents
                     .iter()
-                    .map(|node| node.to_string())
-                    .collect::<Vec<String>>()
-                    .join("\n"),
-                _ => ast.to_string(),
+                    .map(|id| short_id(id))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                short_id(&id)
+            );
+            let changes = commit.changes();
+            if changes.is_empty() {
+                println!("  (No changes - likely initial commit)");
+            }
+            let parent = match commit.first_parent() {
+                Some(parent) => reconstruct(parent, &commits_dir, &snapshots_dir)?,
+                None => Tree::new(),
             };
+            for (path, change) in &changes {
+                println!("{}", change.summary(path));
+                let Some(edit) = change.edit() else {
+                    continue;
+                };
+                if edit.instructions.is_empty() && edit.layout.is_some() {
+                    println!("  (No instructions - only the formatting changed)");
+                } else if edit.instructions.is_empty() {
+                    println!("  (No instructions - likely initial commit or no changes)");
+                }
+                // Describe each instruction against the tree it applies to.
+                let before = match change {
+                    FileChange::Modified(_) => parent.get(path),
+                    FileChange::Renamed { from, .. } => parent.get(from),
+                    _ => None,
+                };
+                let mut ast = Some(before.cloned().unwrap_or_default().ast);
+                for (i, instruction) in edit.instructions.iter().enumerate() {
+                    let Some(before) = ast.as_mut() else {
+                        println!("{}. {}", i + 1, instruction);
+                        continue;
+                    };
+                    println!("{}. {}", i + 1, instruction.locate_in(before));
+                    println!("   {}", instruction.describe(before));
+                    if apply_instruction_mut(before, instruction).is_err() {
+                        ast = None;
+                    }
+                }
+                if let Some(delta) = &edit.layout {
+                    println!(
+                        "Formatting changes, stored apart from the instructions: {}",
+                        delta
+                    );
+                }
+            }
+        }
+        Commands::Merge { branch, message } => {
+            let ours_id = get_current_commit_id(vcdir)?;
+            let theirs_id = read_branch(&branch, vcdir)?;
+            let graph = CommitGraph::load(&[&ours_id, &theirs_id], &commits_dir)?;
+            let base_id = graph
+                .merge_base(&ours_id, &theirs_id)
+                .ok_or_else(|| format!("Branch '{}' shares no history with HEAD.", branch))?;
+
+            let ours = reconstruct(&ours_id, &commits_dir, &snapshots_dir)?;
+            let (working, _) = read_tree(root)?;
+            if has_changes(&ours, &working) {
+                return Err(
+                    "The working directory has uncommitted changes. Commit them before merging."
+                        .into(),
+                );
+            }
+
+            if base_id == theirs_id {
+                println!("Already up to date.");
+            } else if base_id == ours_id {
+                check_out(root, vcdir, &theirs_id)?;
+                set_current_commit_id(&theirs_id, vcdir)?;
+                println!(
+                    "Fast-forwarded to commit {}. Working directory updated.",
+                    short_id(&theirs_id)
+                );
+            } else {
+                let base = reconstruct(&base_id, &commits_dir, &snapshots_dir)?;
+                let theirs = reconstruct(&theirs_id, &commits_dir, &snapshots_dir)?;
+                let format = pretty_config(vcdir)?;
+                let merged = match merge_trees(&base, &ours, &theirs, &format) {
+                    Ok(merged) => merged,
+                    Err(conflicts) => {
+                        for conflict in &conflicts {
+                            println!("CONFLICT: {}", conflict);
+                        }
+                        return Err(format!(
+                            "Merge of '{}' failed with {} conflict(s). Nothing was changed.",
+                            branch,
+                            conflicts.len()
+                        )
+                        .into());
+                    }
+                };
 
-            std::fs::write("code.lisp", code_to_write)?;
-            set_current_commit_id(id, vcdir)?;
-            println!("Checked out commit {}. 'code.lisp' updated.", id);
+                let config = Config::load_all(vcdir)?;
+                let metadata = CommitMetadata {
+                    author: Some(config.author()?),
+                    committer: Some(config.committer()?),
+                    ..CommitMetadata::now(
+                        message.unwrap_or_else(|| format!("Merge branch '{}'", branch)),
+                    )
+                };
+                // Merged files may be pretty-printed; record the layouts they are written with.
+                let written = write_tree(root, &ours, &merged, &format)?;
+                let merge_commit = Commit::new(vec![ours_id, theirs_id.clone()], vec![], metadata)
+                    .with_files(diff_trees(&ours, &written, DiffAlgorithm::Fast))
+                    .with_removed_files(&ours);
+                save_commit(&merge_commit, &commits_dir)?;
+                set_current_commit_id(&merge_commit.id, vcdir)?;
+                println!(
+                    "Merged branch {} (commit {}) as commit {}. Working directory updated.",
+                    branch,
+                    short_id(&theirs_id),
+                    short_id(&merge_commit.id)
+                );
+            }
         }
-        Commands::Debug { id } => {
-            let commit_path = commits_dir.join(format!("{}.json", id));
-            if !commit_path.exists() {
-                return Err(format!("Commit with id '{}' not found.", id).into());
+        Commands::Revert { id, message } => {
+            let id = resolve_commit_id(&id, &commits_dir)?;
+            let commit = load_commit(&id, &commits_dir)?;
+            if commit.parents.len() > 1 {
+                return Err(format!(
+                    "Commit {} is a merge and cannot be reverted.",
+                    short_id(&id)
+                )
+                .into());
             }
+            let Some(parent_id) = commit.first_parent() else {
+                return Err(format!(
+                    "Commit {} is the root commit and cannot be reverted.",
+                    short_id(&id)
+                )
+                .into());
+            };
 
-            let commit = load_commit(id, &commits_dir)?;
+            let head_id = get_current_commit_id(vcdir)?;
+            let head = reconstruct(&head_id, &commits_dir, &snapshots_dir)?;
+            let (working, _) = read_tree(root)?;
+            if has_changes(&head, &working) {
+                return Err(
+                    "The working directory has uncommitted changes. Commit them before reverting."
+                        .into(),
+                );
+            }
+
+            let after = reconstruct(&id, &commits_dir, &snapshots_dir)?;
+            // Commits that do not record what they overwrite are undone from their parent's tree.
+            let before = match undo_changes(&after, &commit) {
+                Some(before) => before,
+                None => reconstruct(parent_id, &commits_dir, &snapshots_dir)?,
+            };
+            let format = pretty_config(vcdir)?;
+            let reverted = if head_id == id {
+                before
+            } else {
+                match merge_trees(&after, &head, &before, &format) {
+                    Ok(reverted) => reverted,
+                    Err(conflicts) => {
+                        for conflict in &conflicts {
+                            println!("CONFLICT: {}", conflict);
+                        }
+                        return Err(format!(
+                            "Revert of commit {} failed with {} conflict(s). Nothing was changed.",
+                            short_id(&id),
+                            conflicts.len()
+                        )
+                        .into());
+                    }
+                }
+            };
 
+            let written = write_tree(root, &head, &reverted, &format)?;
+            let changes = diff_trees(&head, &written, DiffAlgorithm::Fast);
+            if changes.is_empty() {
+                println!(
+                    "Nothing to revert: HEAD already lacks the changes of commit {}.",
+                    short_id(&id)
+                );
+                return Ok(());
+            }
+            let config = Config::load_all(vcdir)?;
+            let subject = commit.metadata.message.lines().next().unwrap_or("");
+            let metadata = CommitMetadata {
+                author: Some(config.author()?),
+                committer: Some(config.committer()?),
+                ..CommitMetadata::now(message.unwrap_or_else(|| {
+                    format!("Revert \"{}\"\n\nThis reverts commit {}.", subject, id)
+                }))
+            };
+            let revert_commit = Commit::new(vec![head_id], vec![], metadata)
+                .with_files(changes)
+                .with_removed_files(&head);
+            let new_id = &revert_commit.id;
+            save_commit(&revert_commit, &commits_dir)?;
+            set_current_commit_id(new_id, vcdir)?;
             println!(
-                "Instructions stored IN commit {}: (Transforming from parent {:?} to {})",
-                id, commit.parent_id, id
+                "Reverted commit {} as commit {}. Working directory updated.",
+                short_id(&id),
+                short_id(new_id)
             );
-            if commit.instructions.is_empty() {
-                println!("  (No instructions - likely initial commit or no changes)");
+            if replay_cost(new_id, &commits_dir, &snapshots_dir)?.needs_snapshot() {
+                save_snapshot(new_id, &written, &snapshots_dir)?;
+                println!("Saved snapshot for commit {}", short_id(new_id));
+            }
+        }
+        Commands::Snapshot { id } => {
+            let id = match id {
+                Some(id) => resolve_commit_id(&id, &commits_dir)?,
+                None => get_current_commit_id(vcdir)?,
+            };
+            let tree = reconstruct(&id, &commits_dir, &snapshots_dir)?;
+            save_manual_snapshot(&id, &tree, &snapshots_dir)?;
+            println!("Saved snapshot for commit {}", short_id(&id));
+        }
+        Commands::Gc { snapshots } => {
+            if !snapshots {
+                println!("Nothing to collect. Pass --snapshots to prune snapshots.");
             } else {
-                for (i, instruction) in commit.instructions.iter().enumerate() {
-                    // Improve display slightly: use () for paths instead of []
-                    let formatted_instruction =
-                        instruction.to_string().replace("[", "(").replace("]", ")");
-                    println!("{}. {}", i + 1, formatted_instruction);
+                let removed = prune_snapshots(&commits_dir, &snapshots_dir)?;
+                if removed.is_empty() {
+                    println!("No snapshots to prune.");
+                } else {
+                    for id in &removed {
+                        println!("Removed snapshot for commit {}", short_id(id));
+                    }
+                }
+            }
+        }
+        Commands::Migrate => {
+            if !is_legacy_repository(vcdir)? {
+                println!("Repository already uses content-addressed commit ids.");
+            } else {
+                for (old_id, new_id) in migrate_repository(vcdir)? {
+                    println!("commit {} -> {}", old_id, short_id(&new_id));
+                }
+                println!("Migrated repository to content-addressed commit ids.");
+            }
+        }
+        Commands::Fmt {
+            paths,
+            check,
+            width,
+        } => {
+            let paths = if paths.is_empty() {
+                list_files(root)?
+            } else {
+                paths
+            };
+            let mut config = pretty_config(vcdir)?;
+            config.width = width.unwrap_or(config.width);
+            // Everything is parsed before anything is written.
+            let mut unformatted = Vec::new();
+            for path in paths {
+                let (file, diagnostics) = read_file(root, &path)?;
+                if !diagnostics.is_empty() {
+                    return Err(format!(
+                        "{}\nCannot format '{}' while it has parse errors.",
+                        diagnostics.join("\n"),
+                        path
+                    )
+                    .into());
+                }
+                let formatted = pretty(&file.ast, &config);
+                if file.layout.render(&file.ast).as_deref() != Some(formatted.as_str()) {
+                    unformatted.push((path, formatted));
+                }
+            }
+            if unformatted.is_empty() {
+                println!("All files are already formatted.");
+            } else if check {
+                for (path, _) in &unformatted {
+                    println!("Not formatted: {}", path);
+                }
+                return Err(format!(
+                    "{} file(s) are not formatted. Run 'trefoil fmt' to format them.",
+                    unformatted.len()
+                )
+                .into());
+            } else {
+                for (path, formatted) in unformatted {
+                    std::fs::write(root.join(&path), formatted)?;
+                    println!("Formatted {}", path);
+                }
+            }
+        }
+        Commands::Config { key, value, global } => {
+            let path = if global {
+                user_config_path().ok_or("Cannot locate the user config: HOME is not set.")?
+            } else if vcdir.exists() {
+                repository_config_path(vcdir)
+            } else {
+                return Err(
+                    "Not a trefoil repository. Pass --global to use the user config.".into(),
+                );
+            };
+            match value {
+                Some(value) => {
+                    let mut config = Config::load(&path)?;
+                    config.set(&key, &value)?;
+                    config.save(&path)?;
+                }
+                None => {
+                    let config = if global {
+                        Config::load(&path)?
+                    } else {
+                        Config::load_all(vcdir)?
+                    };
+                    match config.get(&key) {
+                        Some(value) => println!("{}", value),
+                        None => return Err(format!("'{}' is not set.", key).into()),
+                    }
                 }
             }
         }
@@ -155,9 +801,128 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn load_commit(id: u64, dir: &Path) -> Result<Commit, Box<dyn Error>> {
-    let path = dir.join(format!("{}.json", id));
-    let data = std::fs::read_to_string(path)?;
-    let commit: Commit = serde_json::from_str(&data)?;
-    Ok(commit)
+/// The author, committer, date and message of `commit`, each line after
+/// `prefix`.
+fn print_details(commit: &Commit, prefix: &str) {
+    let metadata = &commit.metadata;
+    if let Some(author) = &metadata.author {
+        println!("{}Author:    {}", prefix, author);
+    }
+    if let Some(committer) = &metadata.committer
+        && metadata.author.as_ref() != Some(committer)
+    {
+        println!("{}Committer: {}", prefix, committer);
+    }
+    if let Some(date) = metadata.date() {
+        println!("{}Date:      {}", prefix, date);
+    }
+    if !metadata.message.is_empty() {
+        println!("{}", prefix.trim_end());
+        for line in metadata.message.lines() {
+            println!("{}", format!("{}    {}", prefix, line).trim_end());
+        }
+    }
+}
+
+/// The changes `commit` made to its first parent, as `diff_text` shows them.
+fn commit_diff(commit: &Commit, vcdir: &Path, colors: bool) -> Result<String, Box<dyn Error>> {
+    let (commits_dir, snapshots_dir) = (vcdir.join("commits"), vcdir.join("snapshots"));
+    let parent = match commit.first_parent() {
+        Some(parent) => reconstruct(parent, &commits_dir, &snapshots_dir)?,
+        None => Tree::new(),
+    };
+    let tree = reconstruct(&commit.id, &commits_dir, &snapshots_dir)?;
+    Ok(diff_text(&parent, &tree, &pretty_config(vcdir)?, colors))
+}
+
+/// The changes from `old` to `new`, file by file, each change shown in the
+/// top-level form it belongs to. Empty if nothing changed.
+fn diff_text(old: &Tree, new: &Tree, config: &PrettyConfig, colors: bool) -> String {
+    let highlight = if colors {
+        Highlight::Colors
+    } else {
+        Highlight::Markers
+    };
+    let mut out = String::new();
+    for (i, (path, change)) in diff_trees(old, new, DiffAlgorithm::Fast).iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(&format!("{}\n", change.summary(path)));
+        let before = match change {
+            FileChange::Renamed { from, .. } => old.get(from),
+            _ => old.get(path),
+        };
+        let before = before.cloned().unwrap_or_default().ast;
+        let after = new.get(path).cloned().unwrap_or_default().ast;
+        let instructions = diff_ast(&before, &after, &mut vec![]);
+        let hunks = hunks(&before, &instructions, config, highlight);
+        if hunks.is_empty() && change.edit().is_some_and(|edit| edit.layout.is_some()) {
+            out.push_str("  (formatting only)\n");
+        }
+        for hunk in hunks {
+            let header = format!("@@ {} @@", hunk.locator);
+            if colors {
+                out.push_str(&format!("\x1b[36m{}\x1b[0m\n", header));
+            } else {
+                out.push_str(&format!("{}\n", header));
+            }
+            out.push_str(&format!("{}\n", hunk.text));
+        }
+    }
+    out
+}
+
+/// Writes the files of commit `id` over those of HEAD. Refuses if the working
+/// directory differs from HEAD, so that no edit or untracked file is lost.
+fn check_out(root: &Path, vcdir: &Path, id: &str) -> Result<(), Box<dyn Error>> {
+    let (commits_dir, snapshots_dir) = (vcdir.join("commits"), vcdir.join("snapshots"));
+    let current = reconstruct(&get_current_commit_id(vcdir)?, &commits_dir, &snapshots_dir)?;
+    if has_changes(&current, &read_tree(root)?.0) {
+        return Err(
+            "The working directory has uncommitted changes. Commit them before checking out."
+                .into(),
+        );
+    }
+    let target = reconstruct(id, &commits_dir, &snapshots_dir)?;
+    write_tree(root, &current, &target, &pretty_config(vcdir)?)?;
+    Ok(())
+}
+
+fn pretty_config(vcdir: &Path) -> Result<PrettyConfig, Box<dyn Error>> {
+    Ok(PrettyConfig::from_config(&Config::load_all(vcdir)?)?)
+}
+
+/// Asks for a commit message in `$VISUAL`/`$EDITOR` (falling back to `vi`),
+/// through `.trefoil/COMMIT_EDITMSG`. Lines starting with `#` are dropped.
+fn edit_message(vcdir: &Path) -> Result<String, Box<dyn Error>> {
+    let path = vcdir.join("COMMIT_EDITMSG");
+    std::fs::write(
+        &path,
+        "\n# Please enter the commit message for your changes. Lines starting\n# with '#' will be ignored, and an empty message aborts the commit.\n",
+    )?;
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // Run through the shell so that editors configured with arguments work.
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status()?;
+    if !status.success() {
+        return Err(format!("Editor '{}' exited with {}.", editor, status).into());
+    }
+    let message = std::fs::read_to_string(&path)?
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string();
+    if message.is_empty() {
+        return Err("Aborting commit due to empty commit message.".into());
+    }
+    Ok(message)
 }
@@ -0,0 +1,506 @@
+use crate::apply::apply_all;
+use crate::ast::Ast;
+use crate::diff::{DiffAlgorithm, diff_ast, lcs};
+use crate::layout::{Layout, TokenLayout};
+use crate::pretty::{PrettyConfig, pretty};
+use crate::vc::{FileChange, FileState, Tree, diff_trees};
+use crate::workdir::parse_file;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+
+pub use crate::transform::{Conflict, ConflictKind, rebase};
+
+/// Three-way merge of two descendants of `base`.
+///
+/// Both sides are diffed against `base`, their instructions are rebased onto
+/// ours and applied to `ours`. Returns every conflict found if any of their
+/// instructions cannot be rebased.
+pub fn merge(base: &Ast, ours: &Ast, theirs: &Ast) -> Result<Ast, Vec<Conflict>> {
+    let our_instructions = diff_ast(base, ours, &mut vec![]);
+    let their_instructions = diff_ast(base, theirs, &mut vec![]);
+    let rebased = rebase(&their_instructions, &our_instructions)?;
+
+    let mut merged = ours.clone();
+    apply_all(&mut merged, &rebased).map_err(|(index, _)| {
+        vec![Conflict {
+            kind: ConflictKind::Inapplicable,
+            ours: None,
+            theirs: rebased[index].clone(),
+        }]
+    })?;
+    Ok(merged)
+}
+
+/// Why the versions of one file in a tree merge could not be combined.
+#[derive(Debug, Clone)]
+pub enum FileConflict {
+    /// Both sides edited the file's contents in ways that conflict.
+    Content(Conflict),
+    /// Both sides added the file, with different contents.
+    BothAdded,
+    /// One side removed the file and the other side changed it.
+    RemovedWhileChanged,
+    /// The sides renamed the file to different paths.
+    BothRenamed { ours: String, theirs: String },
+}
+
+/// A conflict in the file at `path`.
+#[derive(Debug, Clone)]
+pub struct TreeConflict {
+    pub path: String,
+    pub conflict: FileConflict,
+}
+
+impl Display for TreeConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.conflict {
+            FileConflict::Content(conflict) => write!(f, "{}: {}", self.path, conflict),
+            FileConflict::BothAdded => write!(
+                f,
+                "{}: both sides added the file with different contents",
+                self.path
+            ),
+            FileConflict::RemovedWhileChanged => write!(
+                f,
+                "{}: one side removed the file the other side changed",
+                self.path
+            ),
+            FileConflict::BothRenamed { ours, theirs } => write!(
+                f,
+                "{}: one side renamed the file to {} and the other to {}",
+                self.path, ours, theirs
+            ),
+        }
+    }
+}
+
+/// Three-way merge of two descendants of `base`, file by file.
+///
+/// A file renamed on one side is first moved to its new path on the others,
+/// so that edits on the other side follow it. A file only one side changed
+/// (added, removed or edited) is then taken from that side; a file both
+/// sides edited is merged with `merge` and laid out with `merged_layout`.
+pub fn merge_trees(
+    base: &Tree,
+    ours: &Tree,
+    theirs: &Tree,
+    config: &PrettyConfig,
+) -> Result<Tree, Vec<TreeConflict>> {
+    let mut conflicts = Vec::new();
+    let (our_renames, their_renames) = (renames(base, ours), renames(base, theirs));
+    for (from, to) in &their_renames {
+        if let Some(ours) = our_renames.get(from).filter(|ours| *ours != to) {
+            conflicts.push(TreeConflict {
+                path: from.clone(),
+                conflict: FileConflict::BothRenamed {
+                    ours: ours.clone(),
+                    theirs: to.clone(),
+                },
+            });
+        }
+    }
+    // A rename onto a path the base already has is left to the per-path merge.
+    let moves: BTreeMap<&String, &String> = our_renames
+        .iter()
+        .chain(&their_renames)
+        .filter(|(from, to)| our_renames.get(*from).is_none_or(|ours| ours == *to))
+        .filter(|(from, to)| their_renames.get(*from).is_none_or(|theirs| theirs == *to))
+        .filter(|(_, to)| !base.contains_key(*to))
+        .collect();
+    let (base, ours, theirs) = (
+        &follow(base, &moves),
+        &follow(ours, &moves),
+        &follow(theirs, &moves),
+    );
+
+    let paths: BTreeSet<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    let mut merged = Tree::new();
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let file = if o == b || o == t {
+            t
+        } else if t == b {
+            o
+        } else {
+            match (b, o, t) {
+                (Some(b), Some(o), Some(t)) => match merge(&b.ast, &o.ast, &t.ast) {
+                    Ok(ast) if ast == o.ast => Some(o),
+                    Ok(ast) if ast == t.ast => Some(t),
+                    Ok(ast) => {
+                        let layout = merged_layout(&ast, o, t, config);
+                        merged.insert(path.clone(), FileState { ast, layout });
+                        continue;
+                    }
+                    Err(content) => {
+                        conflicts.extend(content.into_iter().map(|conflict| TreeConflict {
+                            path: path.clone(),
+                            conflict: FileConflict::Content(conflict),
+                        }));
+                        continue;
+                    }
+                },
+                (None, _, _) => {
+                    conflicts.push(TreeConflict {
+                        path: path.clone(),
+                        conflict: FileConflict::BothAdded,
+                    });
+                    continue;
+                }
+                (Some(_), _, _) => {
+                    conflicts.push(TreeConflict {
+                        path: path.clone(),
+                        conflict: FileConflict::RemovedWhileChanged,
+                    });
+                    continue;
+                }
+            }
+        };
+        if let Some(file) = file {
+            merged.insert(path.clone(), file.clone());
+        }
+    }
+    if conflicts.is_empty() {
+        Ok(merged)
+    } else {
+        Err(conflicts)
+    }
+}
+
+/// The files `side` renamed from `base`, by old path, with their new paths.
+fn renames(base: &Tree, side: &Tree) -> BTreeMap<String, String> {
+    diff_trees(base, side, DiffAlgorithm::Fast)
+        .into_iter()
+        .filter_map(|(path, change)| match change {
+            FileChange::Renamed { from, .. } => Some((from, path)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `tree` with each file at a key of `moves` moved to its value, unless a
+/// file is already there.
+fn follow(tree: &Tree, moves: &BTreeMap<&String, &String>) -> Tree {
+    let mut moved = tree.clone();
+    for (&from, &to) in moves {
+        if !moved.contains_key(to)
+            && let Some(file) = moved.remove(from)
+        {
+            moved.insert(to.clone(), file);
+        }
+    }
+    moved
+}
+
+/// A layout for `merged`, a merge of `ours` and `theirs`. Top-level forms
+/// found unchanged on a side keep their entries from that side, ours first;
+/// the others are pretty-printed on lines of their own. The empty layout,
+/// for the whole file to be pretty-printed, if neither side's layout fits
+/// its tree or the result would not read back as `merged`.
+fn merged_layout(
+    merged: &Ast,
+    ours: &FileState,
+    theirs: &FileState,
+    config: &PrettyConfig,
+) -> Layout {
+    let forms = merged.children();
+    let mut entries: Vec<Option<&[TokenLayout]>> = vec![None; forms.len()];
+    let mut fits = false;
+    for side in [ours, theirs] {
+        let Some(side_entries) = side.layout.forms(&side.ast) else {
+            continue;
+        };
+        fits = true;
+        let open: Vec<usize> = (0..forms.len()).filter(|&i| entries[i].is_none()).collect();
+        let open_forms: Vec<&Ast> = open.iter().map(|&i| &forms[i]).collect();
+        let side_forms: Vec<&Ast> = side.ast.children().iter().collect();
+        for (s, k) in lcs(&side_forms, &open_forms) {
+            entries[open[k]] = Some(side_entries[s]);
+        }
+    }
+    if !fits {
+        return Layout::default();
+    }
+
+    let mut layout = Layout {
+        tokens: Vec::new(),
+        trailing: match ours.layout.render(&ours.ast) {
+            Some(_) => ours.layout.trailing.clone(),
+            None => theirs.layout.trailing.clone(),
+        },
+    };
+    for (i, (form, entries)) in forms.iter().zip(entries).enumerate() {
+        match entries {
+            Some(entries) => layout.tokens.extend_from_slice(entries),
+            None => {
+                let single = Ast::list(vec![form.clone()]);
+                let (printed, _) = parse_file(&pretty(&single, config));
+                if printed.ast != single {
+                    return Layout::default();
+                }
+                let mut tokens = printed.layout.tokens;
+                if let Some(first) = tokens.first_mut() {
+                    first.before = if i == 0 {
+                        String::new()
+                    } else {
+                        "\n".to_string()
+                    };
+                }
+                layout.tokens.extend(tokens);
+            }
+        }
+    }
+    // Entries taken from different places may run two tokens together.
+    match layout.render(merged) {
+        Some(text) if parse_file(&text).0.ast == *merged => layout,
+        _ => Layout::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, tokenize};
+    use crate::workdir::parse_file;
+    use proptest::prelude::*;
+
+    fn ast(code: &str) -> Ast {
+        parse(&tokenize(code)).unwrap()
+    }
+
+    #[test]
+    fn test_merge_edits_in_different_forms() {
+        let base = ast("(define x 1) (define y 2)");
+        let ours = ast("(define x 10) (define y 2)");
+        let theirs = ast("(define x 1) (define y 20)");
+        assert_eq!(
+            merge(&base, &ours, &theirs).unwrap(),
+            ast("(define x 10) (define y 20)")
+        );
+    }
+
+    #[test]
+    fn test_merge_inserts_on_both_sides() {
+        let base = ast("(a) (b)");
+        let ours = ast("(first) (a) (b)");
+        let theirs = ast("(a) (b) (last)");
+        assert_eq!(
+            merge(&base, &ours, &theirs).unwrap(),
+            ast("(first) (a) (b) (last)")
+        );
+    }
+
+    #[test]
+    fn test_merge_inserts_at_same_index_puts_ours_first() {
+        let base = ast("(a)");
+        let ours = ast("(a) (ours)");
+        let theirs = ast("(a) (theirs)");
+        assert_eq!(
+            merge(&base, &ours, &theirs).unwrap(),
+            ast("(a) (ours) (theirs)")
+        );
+    }
+
+    #[test]
+    fn test_merge_same_change_on_both_sides() {
+        let base = ast("(define x 1)");
+        let both = ast("(define x 2) (print x)");
+        assert_eq!(merge(&base, &both, &both).unwrap(), both);
+    }
+
+    #[test]
+    fn test_merge_edit_inside_moved_form() {
+        let base = ast("(define a 1) (define b 2) (define c 3)");
+        let ours = ast("(define c 3) (define a 1) (define b 2)");
+        let theirs = ast("(define a 1) (define b 2) (define c 30)");
+        assert_eq!(
+            merge(&base, &ours, &theirs).unwrap(),
+            ast("(define c 30) (define a 1) (define b 2)")
+        );
+    }
+
+    #[test]
+    fn test_merge_conflicting_updates() {
+        let base = ast("(define x 1)");
+        let ours = ast("(define x 2)");
+        let theirs = ast("(define x 3)");
+        let conflicts = merge(&base, &ours, &theirs).unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::BothChanged);
+    }
+
+    #[test]
+    fn test_merge_delete_versus_edit() {
+        let base = ast("(define x 1) (define y 2)");
+        let ours = ast("(define y 2)");
+        let theirs = ast("(define x 10) (define y 2)");
+        let conflicts = merge(&base, &ours, &theirs).unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::RemovedWhileEdited);
+    }
+
+    #[test]
+    fn test_merge_trees_file_by_file() {
+        let file = |code: &str| FileState {
+            ast: ast(code),
+            ..FileState::default()
+        };
+        let tree = |files: &[(&str, &str)]| -> Tree {
+            files
+                .iter()
+                .map(|(path, code)| (path.to_string(), file(code)))
+                .collect()
+        };
+        let base = tree(&[
+            ("a.lisp", "(a 1) (b 2)"),
+            ("b.lisp", "(x)"),
+            ("c.lisp", "(c)"),
+        ]);
+        let ours = tree(&[
+            ("a.lisp", "(a 10) (b 2)"),
+            ("b.lisp", "(x)"),
+            ("new.lisp", "(n)"),
+        ]);
+        let theirs = tree(&[
+            ("a.lisp", "(a 1) (b 20)"),
+            ("b.lisp", "(y)"),
+            ("c.lisp", "(c)"),
+        ]);
+        let merged = merge_trees(&base, &ours, &theirs, &PrettyConfig::default()).unwrap();
+        assert_eq!(
+            merged,
+            tree(&[
+                ("a.lisp", "(a 10) (b 20)"),
+                ("b.lisp", "(y)"),
+                ("new.lisp", "(n)")
+            ])
+        );
+
+        let theirs = tree(&[
+            ("a.lisp", "(a 1) (b 2)"),
+            ("c.lisp", "(c 3)"),
+            ("new.lisp", "(m)"),
+        ]);
+        let conflicts: Vec<String> = merge_trees(&base, &ours, &theirs, &PrettyConfig::default())
+            .unwrap_err()
+            .iter()
+            .map(|conflict| conflict.to_string())
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![
+                "c.lisp: one side removed the file the other side changed",
+                "new.lisp: both sides added the file with different contents",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_trees_follows_renames() {
+        let source = "(define (f x) (* x x))\n(define (g y) (+ y 1))\n";
+        let base = Tree::from([("a.lisp".to_string(), parse_file(source).0)]);
+        let ours = Tree::from([("lib/a.lisp".to_string(), parse_file(source).0)]);
+        let edited = "(define (f x) (* x x))\n(define (g y) (+ y 2))\n";
+        let theirs = Tree::from([("a.lisp".to_string(), parse_file(edited).0)]);
+        let merged = merge_trees(&base, &ours, &theirs, &PrettyConfig::default()).unwrap();
+        assert_eq!(
+            merged,
+            Tree::from([("lib/a.lisp".to_string(), parse_file(edited).0)])
+        );
+
+        let theirs = Tree::from([("b.lisp".to_string(), parse_file(source).0)]);
+        let conflicts: Vec<String> = merge_trees(&base, &ours, &theirs, &PrettyConfig::default())
+            .unwrap_err()
+            .iter()
+            .map(|conflict| conflict.to_string())
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec!["a.lisp: one side renamed the file to lib/a.lisp and the other to b.lisp"]
+        );
+    }
+
+    #[test]
+    fn test_merge_trees_keeps_layouts_of_untouched_forms() {
+        let tree = |source: &str| Tree::from([("a.lisp".to_string(), parse_file(source).0)]);
+        let base = tree("(define  x 1) ; one\n\n(define y\n    2)\n(z   3)\n");
+        let ours = tree("(define  x 10) ; one\n\n(define y\n    2)\n(z   3)\n");
+        let theirs = tree("(define  x 1) ; one\n\n(define y\n    2)\n(z   30)\n");
+        let merged = merge_trees(&base, &ours, &theirs, &PrettyConfig::default()).unwrap();
+        let file = &merged["a.lisp"];
+        assert_eq!(
+            file.layout.render(&file.ast).unwrap(),
+            "(define  x 10) ; one\n\n(define y\n    2)\n(z   30)\n"
+        );
+
+        // A form both sides changed is pretty-printed on its own.
+        let ours = tree("(define  x 1) ; one\n\n(define y\n    2)\n(z   3 4)\n");
+        let merged = merge_trees(&base, &ours, &theirs, &PrettyConfig::default()).unwrap();
+        let file = &merged["a.lisp"];
+        assert_eq!(
+            file.layout.render(&file.ast).unwrap(),
+            "(define  x 1) ; one\n\n(define y\n    2)\n(z 30 4)\n"
+        );
+    }
+
+    fn ast_strategy(depth: u32) -> BoxedStrategy<Ast> {
+        // A small alphabet so that both sides often touch the same atoms.
+        let atom = prop::sample::select(vec!["a", "b", "c"])
+            .prop_map(|s| Ast::Atom(s.to_string()))
+            .boxed();
+        if depth == 0 {
+            atom
+        } else {
+            prop_oneof![
+                atom,
+                prop::collection::vec(ast_strategy(depth - 1), 0..5).prop_map(Ast::list)
+            ]
+            .boxed()
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn merge_with_unchanged_side(
+            base in ast_strategy(3),
+            ours in ast_strategy(3),
+            theirs in ast_strategy(3),
+        ) {
+            assert_eq!(merge(&base, &ours, &base).unwrap(), ours);
+            assert_eq!(merge(&base, &base, &theirs).unwrap(), theirs);
+            assert_eq!(merge(&base, &ours, &ours).unwrap(), ours);
+        }
+
+        /// Each top-level form `(define fN <body>)` keeps its body (0), takes
+        /// its body from `o` on our side (1) or from `t` on theirs (2), as
+        /// `mask` says; the merge has every edit.
+        #[test]
+        fn merge_disjoint_edits(
+            base in prop::collection::vec(ast_strategy(2), 0..6),
+            o in prop::collection::vec(ast_strategy(2), 6),
+            t in prop::collection::vec(ast_strategy(2), 6),
+            mask in prop::collection::vec(0..3usize, 6),
+        ) {
+            let define = |i: usize, body: &Ast| {
+                Ast::list(vec![
+                    Ast::Atom("define".to_string()),
+                    Ast::Atom(format!("f{}", i)),
+                    body.clone(),
+                ])
+            };
+            let version = |sides: &[usize]| -> Ast {
+                let forms = base.iter().enumerate().map(|(i, body)| match mask[i] {
+                    1 if sides.contains(&1) => define(i, &o[i]),
+                    2 if sides.contains(&2) => define(i, &t[i]),
+                    _ => define(i, body),
+                });
+                Ast::list(forms.collect())
+            };
+            let merged = merge(&version(&[]), &version(&[1]), &version(&[2]));
+            prop_assert_eq!(merged.unwrap(), version(&[1, 2]));
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use crate::instruction::Instruction;
+use crate::refs::{Head, list_branches, read_head, write_branch, write_head};
+use crate::vc::{Commit, CommitMetadata, save_commit};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+/// A commit as written by repositories that numbered commits `0, 1, 2, ...`.
+/// Accepts the single `parent_id` (and `merge_parent`) of repositories
+/// written before `parents` existed.
+#[derive(Deserialize)]
+struct LegacyCommit {
+    id: u64,
+    #[serde(default)]
+    parents: Option<Vec<u64>>,
+    #[serde(default)]
+    parent_id: Option<u64>,
+    #[serde(default)]
+    merge_parent: Option<u64>,
+    instructions: Vec<Instruction>,
+    timestamp: u64,
+}
+
+impl LegacyCommit {
+    fn parents(&self) -> Vec<u64> {
+        self.parents.clone().unwrap_or_else(|| {
+            self.parent_id
+                .into_iter()
+                .chain(self.merge_parent)
+                .collect()
+        })
+    }
+}
+
+/// Where a migration records the new id of every old commit once all new
+/// commits are written, so that a run interrupted while removing old files
+/// can pick up where it left off.
+const MIGRATION_MAP: &str = "migration.json";
+
+fn legacy_ids(dir: &Path) -> Result<Vec<u64>, Box<dyn Error>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut ids = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.path().file_stem()?.to_str()?.parse::<u64>().ok())
+        .collect::<Vec<u64>>();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Whether the repository at `vcdir` still stores integer commit ids, or
+/// has a migration that did not finish.
+pub fn is_legacy_repository(vcdir: &Path) -> Result<bool, Box<dyn Error>> {
+    Ok(!legacy_ids(&vcdir.join("commits"))?.is_empty() || vcdir.join(MIGRATION_MAP).exists())
+}
+
+/// Rewrites an integer-id repository to content-addressed ids: commits,
+/// snapshots, branches and HEAD. Returns the old and new id of every commit.
+///
+/// New commits are all written, and the mapping recorded, before any old
+/// file is removed, so an interrupted migration can be run again.
+pub fn migrate_repository(vcdir: &Path) -> Result<Vec<(u64, String)>, Box<dyn Error>> {
+    let commits_dir = vcdir.join("commits");
+    let snapshots_dir = vcdir.join("snapshots");
+    let map_path = vcdir.join(MIGRATION_MAP);
+
+    // Commits an interrupted run converted, whose old files may be gone.
+    let mut new_ids: BTreeMap<u64, String> = match std::fs::read_to_string(&map_path) {
+        Ok(data) => serde_json::from_str(&data)?,
+        Err(_) => BTreeMap::new(),
+    };
+    // Integer ids were handed out as max + 1, so parents precede their children.
+    for old_id in legacy_ids(&commits_dir)? {
+        if new_ids.contains_key(&old_id) {
+            continue;
+        }
+        let data = std::fs::read_to_string(commits_dir.join(format!("{}.json", old_id)))?;
+        let legacy: LegacyCommit = serde_json::from_str(&data)?;
+        if legacy.id != old_id {
+            return Err(format!("Commit file {}.json holds commit {}.", old_id, legacy.id).into());
+        }
+        let parents = legacy
+            .parents()
+            .iter()
+            .map(|parent| {
+                new_ids
+                    .get(parent)
+                    .cloned()
+                    .ok_or_else(|| format!("Commit {} has a missing parent {}.", old_id, parent))
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+        let metadata = CommitMetadata {
+            timestamp: legacy.timestamp,
+            ..CommitMetadata::default()
+        };
+        let commit = Commit::new(parents, legacy.instructions, metadata);
+        save_commit(&commit, &commits_dir)?;
+        new_ids.insert(old_id, commit.id);
+    }
+    std::fs::write(&map_path, serde_json::to_string(&new_ids)?)?;
+    let rename = |old: &str| -> Result<String, Box<dyn Error>> {
+        let old_id = old.parse::<u64>()?;
+        new_ids
+            .get(&old_id)
+            .cloned()
+            .ok_or_else(|| format!("Commit {} not found.", old_id).into())
+    };
+
+    for (name, id) in list_branches(vcdir)? {
+        if id.parse::<u64>().is_ok() {
+            write_branch(&name, &rename(&id)?, vcdir)?;
+        }
+    }
+    if let Head::Detached(id) = read_head(vcdir)?
+        && id.parse::<u64>().is_ok()
+    {
+        write_head(&Head::Detached(rename(&id)?), vcdir)?;
+    }
+
+    for old_id in legacy_ids(&snapshots_dir)? {
+        let old_path = snapshots_dir.join(format!("{}.json", old_id));
+        if let Some(new_id) = new_ids.get(&old_id) {
+            std::fs::rename(&old_path, snapshots_dir.join(format!("{}.json", new_id)))?;
+        } else {
+            std::fs::remove_file(old_path)?;
+        }
+    }
+    for old_id in new_ids.keys() {
+        let path = commits_dir.join(format!("{}.json", old_id));
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    std::fs::remove_file(map_path)?;
+    Ok(new_ids.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refs::read_branch;
+    use crate::testutil::TempDir;
+    use crate::vc::load_commit;
+
+    #[test]
+    fn test_legacy_commit_parents() {
+        let commit: LegacyCommit =
+            serde_json::from_str(r#"{"id":2,"parent_id":1,"instructions":[],"timestamp":0}"#)
+                .unwrap();
+        assert_eq!(commit.parents(), vec![1]);
+
+        let root: LegacyCommit =
+            serde_json::from_str(r#"{"id":0,"parent_id":null,"instructions":[],"timestamp":0}"#)
+                .unwrap();
+        assert!(root.parents().is_empty());
+
+        let merge: LegacyCommit = serde_json::from_str(
+            r#"{"id":4,"parent_id":2,"merge_parent":3,"instructions":[],"timestamp":0}"#,
+        )
+        .unwrap();
+        assert_eq!(merge.parents(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_migrate_repository() {
+        let temp = TempDir::new("migrate");
+        let vcdir = temp.path().to_path_buf();
+        let commits_dir = vcdir.join("commits");
+        std::fs::create_dir_all(&commits_dir).unwrap();
+        std::fs::create_dir_all(vcdir.join("snapshots")).unwrap();
+        let commits = [
+            r#"{"id":0,"parent_id":null,"instructions":[],"timestamp":0}"#,
+            r#"{"id":1,"parent_id":0,"instructions":[{"Insert":{"path":[],"index":0,"node":{"Atom":"x"}}}],"timestamp":0}"#,
+            r#"{"id":2,"parents":[1],"instructions":[],"timestamp":5}"#,
+        ];
+        for (id, json) in commits.iter().enumerate() {
+            std::fs::write(commits_dir.join(format!("{}.json", id)), json).unwrap();
+        }
+        std::fs::write(vcdir.join("snapshots").join("1.json"), r#"{"List":[]}"#).unwrap();
+        write_branch("main", "2", &vcdir).unwrap();
+        write_head(&Head::Branch("main".to_string()), &vcdir).unwrap();
+        assert!(is_legacy_repository(&vcdir).unwrap());
+
+        let mapping = migrate_repository(&vcdir).unwrap();
+        assert_eq!(mapping.len(), 3);
+        assert!(!is_legacy_repository(&vcdir).unwrap());
+        let head = read_branch("main", &vcdir).unwrap();
+        assert_eq!(head, mapping[2].1);
+        let commit = load_commit(&head, &commits_dir).unwrap();
+        assert_eq!(commit.parents, vec![mapping[1].1.clone()]);
+        assert!(
+            vcdir
+                .join("snapshots")
+                .join(format!("{}.json", mapping[1].1))
+                .exists()
+        );
+
+        // Interrupted after removing the first two old commits.
+        std::fs::write(commits_dir.join("2.json"), commits[2]).unwrap();
+        let recorded: BTreeMap<u64, String> = mapping.iter().cloned().collect();
+        std::fs::write(
+            vcdir.join(MIGRATION_MAP),
+            serde_json::to_string(&recorded).unwrap(),
+        )
+        .unwrap();
+        assert!(is_legacy_repository(&vcdir).unwrap());
+        assert_eq!(migrate_repository(&vcdir).unwrap(), mapping);
+        assert!(!is_legacy_repository(&vcdir).unwrap());
+        assert!(!commits_dir.join("2.json").exists());
+    }
+}
@@ -1,72 +1,621 @@
-use crate::ast::Ast;
+use crate::ast::{Ast, Delimiter, LiteralKind, ReaderMacro, Trivia};
+use std::fmt::{Display, Formatter};
 use std::iter::Peekable;
 use std::slice::Iter;
 
-pub fn tokenize(code: &str) -> Vec<String> {
-    code.replace('(', " ( ")
-        .replace(')', " ) ")
-        .split_whitespace()
-        .map(|s| s.to_string())
-        .collect()
+/// Token standing for one or more blank lines between two forms.
+const BLANK_LINE: &str = "\n";
+
+/// A byte range `start..end` of the source text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
-pub fn parse(tokens: &[String]) -> Result<Ast, String> {
-    let mut iter = tokens.iter().peekable();
-    let mut top_level_nodes = Vec::new();
+/// A token, where it starts and the 1-based line and column (in characters)
+/// of its first character.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub text: String,
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+    /// A block comment whose nesting never closes, so it runs to the end of
+    /// the input.
+    pub unclosed: bool,
+}
+
+/// Why source text could not be parsed. `Display` gives the bare message;
+/// `render` adds the location and the offending source lines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A closing delimiter with no list open.
+    UnexpectedClose {
+        delimiter: Delimiter,
+        span: Span,
+    },
+    /// A list closed with a different delimiter than it was opened with.
+    MismatchedClose {
+        open: Delimiter,
+        open_span: Span,
+        close: Delimiter,
+        span: Span,
+    },
+    /// A list still open at the end of the input; `span` is its opening delimiter.
+    UnclosedList {
+        delimiter: Delimiter,
+        span: Span,
+    },
+    /// A reader macro prefix with no form after it.
+    MissingQuotedForm {
+        reader_macro: ReaderMacro,
+        span: Span,
+    },
+    /// A `#|` without its `|#`.
+    UnclosedComment {
+        span: Span,
+    },
+    /// A `"` without its closing quote.
+    UnclosedString {
+        span: Span,
+    },
+    UnknownEscape {
+        escape: char,
+        span: Span,
+    },
+    InvalidUnicodeEscape {
+        span: Span,
+    },
+}
+
+impl ParseError {
+    /// Where the error is.
+    pub fn span(&self) -> Span {
+        self.labels()[0].0
+    }
+
+    /// The spans worth pointing at, the error itself first.
+    fn labels(&self) -> Vec<(Span, String)> {
+        match self {
+            ParseError::UnexpectedClose { span, .. } => {
+                vec![(*span, "no list is open here".to_string())]
+            }
+            ParseError::MismatchedClose {
+                open,
+                open_span,
+                span,
+                ..
+            } => vec![
+                (*span, format!("expected '{}'", open.close())),
+                (*open_span, "list opened here".to_string()),
+            ],
+            ParseError::UnclosedList { span, .. } => {
+                vec![(*span, "this list is never closed".to_string())]
+            }
+            ParseError::MissingQuotedForm { span, .. } => {
+                vec![(*span, "expected a form after this".to_string())]
+            }
+            ParseError::UnclosedComment { span } => {
+                vec![(*span, "comment starts here".to_string())]
+            }
+            ParseError::UnclosedString { span } => {
+                vec![(*span, "string starts here".to_string())]
+            }
+            ParseError::UnknownEscape { span, .. } => {
+                vec![(*span, "unknown escape".to_string())]
+            }
+            ParseError::InvalidUnicodeEscape { span } => {
+                vec![(*span, "expected '\\u{' hex digits '}'".to_string())]
+            }
+        }
+    }
+
+    /// The message followed by `path:line:column` and each labelled source
+    /// line, with a caret under the error and dashes under related spans:
+    ///
+    /// ```text
+    /// Mismatched closing parenthesis ')' for list opened with '['; expected ']'.
+    ///  --> code.lisp:1:10
+    ///   |
+    /// 1 | (let [x 1)
+    ///   |          ^ expected ']'
+    ///   |      - list opened here
+    /// ```
+    pub fn render(&self, source: &str, path: &str) -> String {
+        let labels = self.labels();
+        let (line, column) = line_column(source, labels[0].0.start);
+        let width = labels
+            .iter()
+            .map(|(span, _)| line_column(source, span.start).0.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(width);
+        let mut out = format!(
+            "{}\n{}--> {}:{}:{}\n{} |",
+            self, gutter, path, line, column, gutter
+        );
+        let mut shown_line = None;
+        for (i, (span, label)) in labels.iter().enumerate() {
+            let (line, column) = line_column(source, span.start);
+            let text = source.lines().nth(line - 1).unwrap_or("");
+            if shown_line != Some(line) {
+                out.push_str(&format!("\n{:>width$} | {}", line, text));
+                shown_line = Some(line);
+            }
+            // Underline to the end of the span or of its line, whichever is first.
+            let rest: String = text.chars().skip(column - 1).collect();
+            let length = source[span.start..span.end.max(span.start)]
+                .chars()
+                .count()
+                .clamp(1, rest.chars().count().max(1));
+            let mark = if i == 0 { "^" } else { "-" };
+            out.push_str(&format!(
+                "\n{} | {}{} {}",
+                gutter,
+                " ".repeat(column - 1),
+                mark.repeat(length),
+                label
+            ));
+        }
+        out
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnexpectedClose { delimiter, .. } => write!(
+                f,
+                "Unexpected closing {} '{}' at top level.",
+                delimiter.name(),
+                delimiter.close()
+            ),
+            ParseError::MismatchedClose { open, close, .. } => write!(
+                f,
+                "Mismatched closing {} '{}' for list opened with '{}'; expected '{}'.",
+                close.name(),
+                close.close(),
+                open.open(),
+                open.close()
+            ),
+            ParseError::UnclosedList { delimiter, .. } => write!(
+                f,
+                "Missing closing {} '{}' for list.",
+                delimiter.name(),
+                delimiter.close()
+            ),
+            ParseError::MissingQuotedForm { reader_macro, .. } => {
+                write!(f, "Expected a form after '{}'.", reader_macro.prefix())
+            }
+            ParseError::UnclosedComment { .. } => {
+                write!(f, "Missing '|#' to close block comment '#|'.")
+            }
+            ParseError::UnclosedString { .. } => {
+                write!(f, "Missing closing '\"' for string literal.")
+            }
+            ParseError::UnknownEscape { escape, .. } => write!(
+                f,
+                "Unknown escape sequence '\\{}' in string literal.",
+                escape
+            ),
+            ParseError::InvalidUnicodeEscape { .. } => {
+                write!(f, "Invalid '\\u{{...}}' escape in string literal.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
 
-    while iter.peek().is_some() {
-        if iter.peek() == Some(&&")".to_string()) {
-            return Err("Unexpected closing parenthesis ')' at top level.".to_string());
+/// The 1-based line and column (in characters) of byte `offset` in `source`.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Splits `code` into brackets, reader macro prefixes (`'`, `` ` ``, `,`,
+/// `,@`, `#'`), atoms, string literals and trivia. Strings and comments are
+/// kept with their delimiters (`"..."` with escapes intact, `;...`, `#|...|#`
+/// with nesting) and a run of blank lines between two forms becomes a single
+/// `"\n"` token.
+pub fn tokenize(code: &str) -> Vec<Token> {
+    let chars: Vec<char> = code.chars().collect();
+    // Byte offset of every character, and of the end of the input.
+    let offsets: Vec<usize> = code
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain([code.len()])
+        .collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let (mut line, mut column) = (1, 1);
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let mut unclosed = false;
+        match chars[i] {
+            c if c.is_whitespace() => {
+                let mut newlines = 0;
+                while i < chars.len() && chars[i].is_whitespace() {
+                    newlines += usize::from(chars[i] == '\n');
+                    i += 1;
+                }
+                // Blank lines only count between two forms of the same list.
+                let between = tokens.last().is_some_and(|last| {
+                    Delimiter::from_open(&last.text).is_none()
+                        && ReaderMacro::from_prefix(&last.text).is_none()
+                }) && chars.get(i).is_some_and(|&next| !is_close(next));
+                if newlines >= 2 && between {
+                    tokens.push(Token {
+                        text: BLANK_LINE.to_string(),
+                        span: Span {
+                            start: offsets[start],
+                            end: offsets[i],
+                        },
+                        line,
+                        column,
+                        unclosed,
+                    });
+                }
+            }
+            '(' | ')' | '[' | ']' | '{' | '}' | '\'' | '`' => i += 1,
+            ',' => i += if chars.get(i + 1) == Some(&'@') { 2 } else { 1 },
+            '#' if chars.get(i + 1) == Some(&'\'') => i += 2,
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    // Skip the escaped character, which may be a quote.
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(chars.len());
+            }
+            ';' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '#' if chars.get(i + 1) == Some(&'|') => {
+                let mut depth = 0;
+                while i < chars.len() {
+                    if chars[i] == '#' && chars.get(i + 1) == Some(&'|') {
+                        depth += 1;
+                        i += 2;
+                    } else if chars[i] == '|' && chars.get(i + 1) == Some(&'#') {
+                        depth -= 1;
+                        i += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+                unclosed = depth > 0;
+            }
+            _ => {
+                // `#\(`, `#\;` and `#\ ` are character literals, not delimiters.
+                if chars[i] == '#' && chars.get(i + 1) == Some(&'\\') {
+                    i = (i + 3).min(chars.len());
+                }
+                while i < chars.len()
+                    && !chars[i].is_whitespace()
+                    && !matches!(chars[i], '(' | ')' | '[' | ']' | '{' | '}' | ';' | '"')
+                {
+                    i += 1;
+                }
+            }
+        }
+        if !chars[start].is_whitespace() {
+            let text: String = chars[start..i].iter().collect();
+            let text = text.trim_end_matches('\r').to_string();
+            tokens.push(Token {
+                span: Span {
+                    start: offsets[start],
+                    end: offsets[start] + text.len(),
+                },
+                text,
+                line,
+                column,
+                unclosed,
+            });
+        }
+        for &c in &chars[start..i] {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
         }
-        top_level_nodes.push(parse_expression(&mut iter)?);
     }
+    tokens
+}
 
-    Ok(Ast::List(top_level_nodes))
+fn is_close(c: char) -> bool {
+    matches!(c, ')' | ']' | '}')
 }
 
-fn parse_expression(iter: &mut Peekable<Iter<'_, String>>) -> Result<Ast, String> {
-    let token = match iter.next() {
-        Some(token) => token,
-        None => return Err("Unexpected end of input while expecting an expression.".to_string()),
+pub fn parse(tokens: &[Token]) -> Result<Ast, ParseError> {
+    let (ast, errors) = parse_recovering(tokens);
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(ast),
+    }
+}
+
+/// Parses as much as it can, returning every error found. Text that cannot
+/// be read becomes an `Ast::Error` holding it verbatim, and an unclosed list
+/// or a quote with nothing after it gets an `Ast::Missing` where the closing
+/// delimiter or quoted form should be, so the tree still prints back as the
+/// source it came from. A closing delimiter that matches an enclosing list
+/// closes every list inside it; one that matches no open list is an error
+/// node.
+pub fn parse_recovering(tokens: &[Token]) -> (Ast, Vec<ParseError>) {
+    let mut parser = Parser {
+        iter: tokens.iter().peekable(),
+        open: Vec::new(),
+        errors: Vec::new(),
     };
+    let mut top_level_nodes = Vec::new();
+    while let Some(token) = parser.iter.next() {
+        top_level_nodes.push(parser.parse_expression(token));
+    }
+    (Ast::list(top_level_nodes), parser.errors)
+}
 
-    match token.as_str() {
-        "(" => parse_list_items(iter),
-        ")" => Err(
-            "Unexpected closing parenthesis ')' when expecting an expression start or atom."
-                .to_string(),
-        ),
-        _ => Ok(Ast::Atom(token.clone())),
-    }
-}
-
-fn parse_list_items(iter: &mut Peekable<Iter<'_, String>>) -> Result<Ast, String> {
-    let mut list_nodes = Vec::new();
-    loop {
-        match iter.peek() {
-            Some(&token) => {
-                if token == ")" {
-                    iter.next();
-                    return Ok(Ast::List(list_nodes));
-                } else {
-                    list_nodes.push(parse_expression(iter)?);
+struct Parser<'a> {
+    iter: Peekable<Iter<'a, Token>>,
+    /// Delimiters of the lists being read, innermost last.
+    open: Vec<Delimiter>,
+    errors: Vec<ParseError>,
+}
+
+impl Parser<'_> {
+    /// Parses the form starting at `token`. Closing delimiters are consumed by
+    /// the list they close, so one reaching here has no list open.
+    fn parse_expression(&mut self, token: &Token) -> Ast {
+        if let Some(reader_macro) = ReaderMacro::from_prefix(&token.text) {
+            return self.parse_quoted(reader_macro, token);
+        }
+        if let Some(delimiter) = Delimiter::from_open(&token.text) {
+            return self.parse_list_items(delimiter, token);
+        }
+        if let Some(delimiter) = Delimiter::from_close(&token.text) {
+            return self.error(
+                token,
+                ParseError::UnexpectedClose {
+                    delimiter,
+                    span: token.span,
+                },
+            );
+        }
+        match token.text.as_str() {
+            BLANK_LINE => Ast::Trivia(Trivia::BlankLine),
+            _ => parse_atom_or_comment(token).unwrap_or_else(|err| self.error(token, err)),
+        }
+    }
+
+    /// Reads the form after the reader macro prefix `prefix`.
+    fn parse_quoted(&mut self, reader_macro: ReaderMacro, prefix: &Token) -> Ast {
+        let form = match self.iter.peek() {
+            Some(token)
+                if Delimiter::from_close(&token.text).is_none() && !is_trivia_token(token) =>
+            {
+                let token = self.iter.next().unwrap();
+                self.parse_expression(token)
+            }
+            _ => {
+                self.errors.push(ParseError::MissingQuotedForm {
+                    reader_macro,
+                    span: prefix.span,
+                });
+                Ast::Missing("a form".to_string())
+            }
+        };
+        Ast::Quoted(reader_macro, Box::new(form))
+    }
+
+    fn parse_list_items(&mut self, delimiter: Delimiter, open: &Token) -> Ast {
+        self.open.push(delimiter);
+        let mut list_nodes = Vec::new();
+        loop {
+            let Some(&token) = self.iter.peek() else {
+                self.errors.push(ParseError::UnclosedList {
+                    delimiter,
+                    span: open.span,
+                });
+                list_nodes.push(Ast::Missing(delimiter.close().to_string()));
+                break;
+            };
+            match Delimiter::from_close(&token.text) {
+                Some(close) if close == delimiter => {
+                    self.iter.next();
+                    break;
+                }
+                Some(close) => {
+                    self.errors.push(ParseError::MismatchedClose {
+                        open: delimiter,
+                        open_span: open.span,
+                        close,
+                        span: token.span,
+                    });
+                    let outer = &self.open[..self.open.len() - 1];
+                    if outer.contains(&close) {
+                        // Leave the delimiter to the list it closes.
+                        list_nodes.push(Ast::Missing(delimiter.close().to_string()));
+                        break;
+                    }
+                    self.iter.next();
+                    list_nodes.push(Ast::Error(token.text.clone()));
                 }
+                None => {
+                    self.iter.next();
+                    list_nodes.push(self.parse_expression(token));
+                }
+            }
+        }
+        self.open.pop();
+        Ast::List(delimiter, list_nodes)
+    }
+
+    /// Records `err` and keeps `token` as an error node.
+    fn error(&mut self, token: &Token, err: ParseError) -> Ast {
+        self.errors.push(err);
+        Ast::Error(token.text.clone())
+    }
+}
+
+fn is_trivia_token(token: &Token) -> bool {
+    token.text == BLANK_LINE || token.text.starts_with(';') || token.text.starts_with("#|")
+}
+
+fn parse_atom_or_comment(token: &Token) -> Result<Ast, ParseError> {
+    let text = token.text.as_str();
+    if let Some(body) = text.strip_prefix('"') {
+        return parse_string(body, token.span.start + 1).map(Ast::Str);
+    }
+    if let Some(comment) = text.strip_prefix(';') {
+        return Ok(Ast::Trivia(Trivia::LineComment(comment.to_string())));
+    }
+    if let Some(body) = text.strip_prefix("#|") {
+        return match body.strip_suffix("|#") {
+            Some(comment) if !token.unclosed => {
+                Ok(Ast::Trivia(Trivia::BlockComment(comment.to_string())))
             }
-            None => return Err("Missing closing parenthesis ')' for list.".to_string()),
+            _ => Err(ParseError::UnclosedComment {
+                span: Span {
+                    start: token.span.start,
+                    end: token.span.start + 2,
+                },
+            }),
+        };
+    }
+    Ok(classify_atom(text))
+}
+
+/// Reads a bare token as a literal when it looks like one, and as a symbol otherwise.
+pub fn classify_atom(text: &str) -> Ast {
+    match literal_kind(text) {
+        Some(kind) => Ast::Literal(kind, text.to_string()),
+        None => Ast::Atom(text.to_string()),
+    }
+}
+
+fn literal_kind(text: &str) -> Option<LiteralKind> {
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    if !unsigned.is_empty() && unsigned.chars().all(|c| c.is_ascii_digit()) {
+        return Some(LiteralKind::Int);
+    }
+    // Leave `inf`, `NaN` and friends to be symbols.
+    let looks_numeric = unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && unsigned.chars().any(|c| c.is_ascii_digit())
+        && unsigned
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'));
+    if looks_numeric && text.parse::<f64>().is_ok() {
+        return Some(LiteralKind::Float);
+    }
+    match text {
+        "#t" | "#f" | "#true" | "#false" | "true" | "false" => Some(LiteralKind::Bool),
+        _ if text.starts_with("#\\") && text.len() > 2 => Some(LiteralKind::Char),
+        _ if text.len() > 1 && text.starts_with(':') => Some(LiteralKind::Keyword),
+        _ => None,
+    }
+}
+
+/// Turns symbols that read as literals into `Ast::Literal`s, for trees built
+/// from commits made before atoms were classified.
+pub fn reclassify_atoms(ast: &mut Ast) {
+    match ast {
+        Ast::Atom(text) => *ast = classify_atom(text),
+        _ => ast.children_mut().iter_mut().for_each(reclassify_atoms),
+    }
+}
+
+/// Unescapes the body of a string literal, everything after the opening
+/// quote up to and including the closing one. `offset` is where `body`
+/// starts in the source.
+fn parse_string(body: &str, offset: usize) -> Result<String, ParseError> {
+    let mut text = String::new();
+    let mut chars = body.char_indices();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            // The tokenizer ends a string token at its closing quote.
+            '"' => return Ok(text),
+            '\\' => text.push(match chars.next() {
+                Some((_, 'n')) => '\n',
+                Some((_, 't')) => '\t',
+                Some((_, 'r')) => '\r',
+                Some((_, '0')) => '\0',
+                Some((_, c @ ('"' | '\\'))) => c,
+                Some((_, 'u')) => parse_unicode_escape(&mut chars, offset + at)?,
+                Some((_, escape)) => {
+                    return Err(ParseError::UnknownEscape {
+                        escape,
+                        span: Span {
+                            start: offset + at,
+                            end: offset + at + 1 + escape.len_utf8(),
+                        },
+                    });
+                }
+                None => break,
+            }),
+            c => text.push(c),
         }
     }
+    Err(ParseError::UnclosedString {
+        span: Span {
+            start: offset - 1,
+            end: offset,
+        },
+    })
+}
+
+/// Reads the `{hex}` of a `\u{hex}` escape that starts at byte `start`.
+fn parse_unicode_escape(
+    chars: &mut std::str::CharIndices<'_>,
+    start: usize,
+) -> Result<char, ParseError> {
+    let rest = chars.as_str();
+    let invalid = || ParseError::InvalidUnicodeEscape {
+        span: Span {
+            start,
+            end: start + 2,
+        },
+    };
+    let digits = rest
+        .strip_prefix('{')
+        .and_then(|rest| rest.split_once('}'))
+        .map(|(digits, _)| digits)
+        .ok_or_else(invalid)?;
+    let c = u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(invalid)?;
+    // Consume `{`, the digits and `}`.
+    chars.nth(digits.len() + 1);
+    Ok(c)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ast::Ast::{Atom, List};
+    use crate::ast::Ast::{Atom, Literal};
+    use crate::ast::{Delimiter, ReaderMacro};
+
+    fn texts(tokens: Vec<Token>) -> Vec<String> {
+        tokens.into_iter().map(|token| token.text).collect()
+    }
 
     #[test]
     fn test_tokenize_simple() {
         let code = "(define x 10)";
         let tokens = tokenize(code);
-        assert_eq!(tokens, vec!["(", "define", "x", "10", ")"]);
+        assert_eq!(texts(tokens), vec!["(", "define", "x", "10", ")"]);
     }
 
     #[test]
@@ -74,7 +623,7 @@ mod tests {
         let code = "(define x 10)(print x)";
         let tokens = tokenize(code);
         assert_eq!(
-            tokens,
+            texts(tokens),
             vec!["(", "define", "x", "10", ")", "(", "print", "x", ")"]
         );
     }
@@ -85,10 +634,10 @@ mod tests {
         let ast = parse(&tokens).unwrap();
         assert_eq!(
             ast,
-            List(vec![List(vec![
+            Ast::list(vec![Ast::list(vec![
                 Atom("define".to_string()),
                 Atom("x".to_string()),
-                Atom("10".to_string())
+                Literal(LiteralKind::Int, "10".to_string())
             ])])
         );
     }
@@ -100,13 +649,13 @@ mod tests {
         // Expect a top-level list containing the two parsed lists
         assert_eq!(
             ast,
-            List(vec![
-                List(vec![
+            Ast::list(vec![
+                Ast::list(vec![
                     Atom("define".to_string()),
                     Atom("x".to_string()),
-                    Atom("10".to_string())
+                    Literal(LiteralKind::Int, "10".to_string())
                 ]),
-                List(vec![Atom("print".to_string()), Atom("x".to_string())])
+                Ast::list(vec![Atom("print".to_string()), Atom("x".to_string())])
             ])
         );
     }
@@ -117,15 +666,15 @@ mod tests {
         let ast = parse(&tokens).unwrap();
         assert_eq!(
             ast,
-            List(vec![
+            Ast::list(vec![
                 // Top-level list wrapper
-                List(vec![
+                Ast::list(vec![
                     Atom("list".to_string()),
-                    Atom("1".to_string()),
-                    List(vec![
+                    Literal(LiteralKind::Int, "1".to_string()),
+                    Ast::list(vec![
                         Atom("list".to_string()),
-                        Atom("2".to_string()),
-                        Atom("3".to_string())
+                        Literal(LiteralKind::Int, "2".to_string()),
+                        Literal(LiteralKind::Int, "3".to_string())
                     ])
                 ])
             ])
@@ -136,21 +685,21 @@ mod tests {
     fn test_parse_empty_input() {
         let tokens = tokenize("");
         let ast = parse(&tokens).unwrap();
-        assert_eq!(ast, List(vec![]));
+        assert_eq!(ast, Ast::list(vec![]));
     }
 
     #[test]
     fn test_parse_empty_list() {
         let tokens = tokenize("()");
         let ast = parse(&tokens).unwrap();
-        assert_eq!(ast, List(vec![List(vec![])]));
+        assert_eq!(ast, Ast::list(vec![Ast::list(vec![])]));
     }
 
     #[test]
     fn test_parse_multiple_empty_lists() {
         let tokens = tokenize("() ()");
         let ast = parse(&tokens).unwrap();
-        assert_eq!(ast, List(vec![List(vec![]), List(vec![])]));
+        assert_eq!(ast, Ast::list(vec![Ast::list(vec![]), Ast::list(vec![])]));
     }
 
     #[test]
@@ -159,7 +708,7 @@ mod tests {
         let result = parse(&tokens);
         assert!(result.is_err());
         assert_eq!(
-            result.err().unwrap(),
+            result.err().unwrap().to_string(),
             "Unexpected closing parenthesis ')' at top level."
         );
 
@@ -167,7 +716,7 @@ mod tests {
         let result_leading = parse(&tokens_leading);
         assert!(result_leading.is_err());
         assert_eq!(
-            result_leading.err().unwrap(),
+            result_leading.err().unwrap().to_string(),
             "Unexpected closing parenthesis ')' at top level."
         );
     }
@@ -178,7 +727,7 @@ mod tests {
         let result = parse(&tokens);
         assert!(result.is_err());
         assert_eq!(
-            result.err().unwrap(),
+            result.err().unwrap().to_string(),
             "Missing closing parenthesis ')' for list."
         );
 
@@ -186,7 +735,7 @@ mod tests {
         let result_nested = parse(&tokens_nested);
         assert!(result_nested.is_err());
         assert_eq!(
-            result_nested.err().unwrap(),
+            result_nested.err().unwrap().to_string(),
             "Missing closing parenthesis ')' for list."
         );
     }
@@ -197,8 +746,308 @@ mod tests {
         let result = parse(&tokens);
         assert!(result.is_err());
         assert_eq!(
-            result.err().unwrap(),
+            result.err().unwrap().to_string(),
             "Missing closing parenthesis ')' for list."
         );
     }
+
+    #[test]
+    fn test_tokenize_comments_and_blank_lines() {
+        let code =
+            ";; header\n\n(define x ; the x\n  10) #| block #| nested |# |#\n\n\n(print x)\n";
+        assert_eq!(
+            texts(tokenize(code)),
+            vec![
+                ";; header",
+                "\n",
+                "(",
+                "define",
+                "x",
+                "; the x",
+                "10",
+                ")",
+                "#| block #| nested |# |#",
+                "\n",
+                "(",
+                "print",
+                "x",
+                ")"
+            ]
+        );
+        // Blank lines at the edges of a list or the file carry nothing.
+        assert_eq!(texts(tokenize("\n\n(\n\na\n\n)\n\n")), vec!["(", "a", ")"]);
+    }
+
+    #[test]
+    fn test_parse_trivia() {
+        let ast = parse(&tokenize("; top\n(define x ;; why\n  10)\n\n#|b|#")).unwrap();
+        assert_eq!(
+            ast,
+            Ast::list(vec![
+                Ast::Trivia(Trivia::LineComment(" top".to_string())),
+                Ast::list(vec![
+                    Atom("define".to_string()),
+                    Atom("x".to_string()),
+                    Ast::Trivia(Trivia::LineComment("; why".to_string())),
+                    Literal(LiteralKind::Int, "10".to_string())
+                ]),
+                Ast::Trivia(Trivia::BlankLine),
+                Ast::Trivia(Trivia::BlockComment("b".to_string())),
+            ])
+        );
+        assert!(parse(&tokenize("(a #| open")).is_err());
+        // Unclosed nested comments fail whether or not the file ends in `|#`.
+        for source in ["#|#|#", "#| a #| b |#", "#| a #| b |#\n"] {
+            assert!(
+                matches!(
+                    parse(&tokenize(source)),
+                    Err(ParseError::UnclosedComment { .. })
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_display_trivia_roundtrips() {
+        let code = "(define (f x) ; doc\n\n(g x) #|inline|# ;; last\n)";
+        let ast = parse(&tokenize(code)).unwrap();
+        let Ast::List(_, forms) = &ast else {
+            unreachable!()
+        };
+        let printed = forms[0].to_string();
+        assert_eq!(printed, code);
+        assert_eq!(parse(&tokenize(&printed)).unwrap(), ast);
+    }
+
+    #[test]
+    fn test_tokenize_strings() {
+        assert_eq!(
+            texts(tokenize(r#"(display "hello (world) ; not a comment" x)"#)),
+            vec![
+                "(",
+                "display",
+                r#""hello (world) ; not a comment""#,
+                "x",
+                ")"
+            ]
+        );
+        assert_eq!(
+            texts(tokenize(r#"(f "say \"hi\"" "a\\")"#)),
+            vec!["(", "f", r#""say \"hi\"""#, r#""a\\""#, ")"]
+        );
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
+        let ast = parse(&tokenize(r#"("tab\there" "quote\" and \\" "\u{e9}")"#)).unwrap();
+        assert_eq!(
+            ast,
+            Ast::list(vec![Ast::list(vec![
+                Ast::Str("tab\there".to_string()),
+                Ast::Str("quote\" and \\".to_string()),
+                Ast::Str("\u{e9}".to_string()),
+            ])])
+        );
+        assert!(parse(&tokenize(r#"(f "open)"#)).is_err());
+        assert!(parse(&tokenize(r#""\q""#)).is_err());
+    }
+
+    #[test]
+    fn test_display_string_roundtrips() {
+        let text = "line one\n\"two\"\t\\ (three) ; four\u{7}";
+        let printed = Ast::Str(text.to_string()).to_string();
+        assert_eq!(
+            parse(&tokenize(&printed)).unwrap(),
+            Ast::list(vec![Ast::Str(text.to_string())])
+        );
+    }
+
+    #[test]
+    fn test_classify_atoms() {
+        let ast = parse(&tokenize(
+            r"(f 42 -7 3.14 1e3 .5 #t false #\a #\( #\space :key x - 1+ inf)",
+        ))
+        .unwrap();
+        let literal = |kind, text: &str| Ast::Literal(kind, text.to_string());
+        assert_eq!(
+            ast,
+            Ast::list(vec![Ast::list(vec![
+                Atom("f".to_string()),
+                literal(LiteralKind::Int, "42"),
+                literal(LiteralKind::Int, "-7"),
+                literal(LiteralKind::Float, "3.14"),
+                literal(LiteralKind::Float, "1e3"),
+                literal(LiteralKind::Float, ".5"),
+                literal(LiteralKind::Bool, "#t"),
+                literal(LiteralKind::Bool, "false"),
+                literal(LiteralKind::Char, r"#\a"),
+                literal(LiteralKind::Char, r"#\("),
+                literal(LiteralKind::Char, r"#\space"),
+                literal(LiteralKind::Keyword, ":key"),
+                Atom("x".to_string()),
+                Atom("-".to_string()),
+                Atom("1+".to_string()),
+                Atom("inf".to_string()),
+            ])])
+        );
+    }
+
+    #[test]
+    fn test_parse_reader_macros() {
+        assert_eq!(
+            texts(tokenize("`(a ,b ,@c) #'f 'x")),
+            vec!["`", "(", "a", ",", "b", ",@", "c", ")", "#'", "f", "'", "x"]
+        );
+        let quoted = |reader_macro, form| Ast::Quoted(reader_macro, Box::new(form));
+        let ast = parse(&tokenize("`(a ,b ,@c) #'f 'x")).unwrap();
+        assert_eq!(
+            ast,
+            Ast::list(vec![
+                quoted(
+                    ReaderMacro::Quasiquote,
+                    Ast::list(vec![
+                        Atom("a".to_string()),
+                        quoted(ReaderMacro::Unquote, Atom("b".to_string())),
+                        quoted(ReaderMacro::UnquoteSplicing, Atom("c".to_string())),
+                    ])
+                ),
+                quoted(ReaderMacro::Function, Atom("f".to_string())),
+                quoted(ReaderMacro::Quote, Atom("x".to_string())),
+            ])
+        );
+        assert_eq!(ast.get(&[0, 0, 1, 0]), Some(&Atom("b".to_string())));
+        assert!(parse(&tokenize("(a ')")).is_err());
+        assert!(parse(&tokenize("'")).is_err());
+    }
+
+    #[test]
+    fn test_display_reader_macros_roundtrips() {
+        let code = "(defmacro m (x) `(list ',x ,@(rest x) #'car))";
+        let ast = parse(&tokenize(code)).unwrap();
+        assert_eq!(ast.children()[0].to_string(), code);
+    }
+
+    #[test]
+    fn test_parse_brackets() {
+        let code = "(defn f [x] {:a [x] :b {}})";
+        let ast = parse(&tokenize(code)).unwrap();
+        let form = &ast.children()[0];
+        assert!(matches!(
+            form.get(&[2]),
+            Some(Ast::List(Delimiter::Square, _))
+        ));
+        assert!(matches!(
+            form.get(&[3]),
+            Some(Ast::List(Delimiter::Curly, _))
+        ));
+        assert_eq!(form.to_string(), code);
+        assert_eq!(
+            parse(&tokenize("(f [x)")).unwrap_err().to_string(),
+            "Mismatched closing parenthesis ')' for list opened with '['; expected ']'."
+        );
+        assert_eq!(
+            parse(&tokenize("[a")).unwrap_err().to_string(),
+            "Missing closing bracket ']' for list."
+        );
+        assert_eq!(
+            parse(&tokenize("}")).unwrap_err().to_string(),
+            "Unexpected closing brace '}' at top level."
+        );
+    }
+
+    #[test]
+    fn test_parenthesized_lists_keep_their_json() {
+        let ast = parse(&tokenize("(a [b])")).unwrap();
+        let json = serde_json::to_string(&ast).unwrap();
+        assert_eq!(
+            json,
+            r#"{"List":[{"List":[{"Atom":"a"},{"List":{"delimiter":"Square","items":[{"Atom":"b"}]}}]}]}"#
+        );
+        assert_eq!(serde_json::from_str::<Ast>(&json).unwrap(), ast);
+    }
+
+    #[test]
+    fn test_tokens_carry_positions() {
+        let tokens = tokenize("(a\n  \"é\" b)");
+        let positions: Vec<(&str, usize, usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.span.start, t.line, t.column))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                ("(", 0, 1, 1),
+                ("a", 1, 1, 2),
+                ("\"é\"", 5, 2, 3),
+                ("b", 10, 2, 7),
+                (")", 11, 2, 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_parse_errors() {
+        let code = "(define x 1)\n(let [x 1)\n  x)";
+        let err = parse(&tokenize(code)).unwrap_err();
+        assert_eq!(err.span(), Span { start: 22, end: 23 });
+        assert_eq!(
+            err.render(code, "code.lisp"),
+            "Mismatched closing parenthesis ')' for list opened with '['; expected ']'.\n \
+             --> code.lisp:2:10\n  \
+             |\n\
+             2 | (let [x 1)\n  \
+             |          ^ expected ']'\n  \
+             |      - list opened here"
+        );
+
+        let code = "(f \"a\\qb\")\n(g";
+        let err = parse(&tokenize(code)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown escape sequence '\\q' in string literal."
+        );
+        assert!(
+            err.render(code, "code.lisp")
+                .ends_with("1 | (f \"a\\qb\")\n  |      ^^ unknown escape")
+        );
+    }
+
+    #[test]
+    fn test_parse_recovering() {
+        let code = "(define x 1)\n(let [x 1)\n\"open";
+        let (ast, errors) = parse_recovering(&tokenize(code));
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec![
+                "Mismatched closing parenthesis ')' for list opened with '['; expected ']'.",
+                "Missing closing '\"' for string literal.",
+            ]
+        );
+        assert_eq!(
+            ast.get(&[1, 1]),
+            Some(&Ast::List(
+                Delimiter::Square,
+                vec![
+                    Atom("x".to_string()),
+                    Literal(LiteralKind::Int, "1".to_string()),
+                    Ast::Missing("]".to_string()),
+                ]
+            ))
+        );
+        assert_eq!(ast.get(&[2]), Some(&Ast::Error("\"open".to_string())));
+        assert_eq!(ast.children()[1].to_string(), "(let [x 1)");
+        assert_eq!(parse(&tokenize(code)).unwrap_err(), errors[0]);
+
+        let (ast, errors) = parse_recovering(&tokenize("(a ']) (b"));
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            ast.children()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["(a ' ])", "(b"]
+        );
+    }
 }
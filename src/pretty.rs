@@ -0,0 +1,457 @@
+use crate::ast::{Ast, Trivia};
+use crate::config::Config;
+use std::collections::BTreeMap;
+
+/// How `pretty` lays out code.
+///
+/// A rule gives the number of arguments a head symbol keeps on its own line;
+/// the rest form a body indented by `indent`, so with `define = 1`:
+///
+/// ```text
+/// (define (square x)
+///   (* x x))
+/// ```
+///
+/// Calls to other symbols line their arguments up under the first one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrettyConfig {
+    pub width: usize,
+    pub indent: usize,
+    pub rules: BTreeMap<String, usize>,
+}
+
+impl Default for PrettyConfig {
+    fn default() -> Self {
+        let rules = [
+            ("begin", 0),
+            ("case", 1),
+            ("cond", 0),
+            ("define", 1),
+            ("defmacro", 2),
+            ("defun", 2),
+            ("if", 1),
+            ("lambda", 1),
+            ("let", 1),
+            ("let*", 1),
+            ("letrec", 1),
+            ("progn", 0),
+            ("unless", 1),
+            ("when", 1),
+        ];
+        PrettyConfig {
+            width: 80,
+            indent: 2,
+            rules: rules
+                .into_iter()
+                .map(|(head, count)| (head.to_string(), count))
+                .collect(),
+        }
+    }
+}
+
+impl PrettyConfig {
+    /// The defaults, overridden by `format.width`, `format.indent` and one
+    /// `indent.<head> = <count>` rule per head symbol.
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let number = |key: &str, value: &str| {
+            value.parse::<usize>().map_err(|_| {
+                format!(
+                    "Config value {} must be a whole number, not '{}'.",
+                    key, value
+                )
+            })
+        };
+        let mut pretty = PrettyConfig::default();
+        if let Some(width) = config.get("format.width") {
+            pretty.width = number("format.width", width)?;
+        }
+        if let Some(indent) = config.get("format.indent") {
+            pretty.indent = number("format.indent", indent)?;
+        }
+        for (head, count) in config.section("indent") {
+            let count = number(&format!("indent.{}", head), count)?;
+            pretty.rules.insert(head.to_string(), count);
+        }
+        Ok(pretty)
+    }
+}
+
+/// A document in the style of Wadler's "A prettier printer": text with
+/// optional line breaks, which a group takes either all or none of.
+#[derive(Clone, Debug)]
+enum Doc {
+    Text(String),
+    /// A space if the enclosing group fits on the line, a newline otherwise.
+    Line,
+    /// Always a newline; the enclosing groups cannot stay flat.
+    HardLine,
+    Concat(Vec<Doc>),
+    /// Indents the lines inside by a further `n` columns.
+    Nest(usize, Box<Doc>),
+    /// Indents the lines inside to the column where it starts.
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+fn text(s: impl Into<String>) -> Doc {
+    Doc::Text(s.into())
+}
+
+/// The source text of a file whose top-level forms are the children of
+/// `root`, one form per line and each broken to fit `config.width`.
+pub fn pretty(root: &Ast, config: &PrettyConfig) -> String {
+    pretty_decorated(root, config, &|_| None)
+}
+
+/// The text to print before and after the node at a path from the root, if
+/// any. ANSI colour codes in it take up no width.
+pub(crate) type Decorate<'a> = dyn Fn(&[usize]) -> Option<(String, String)> + 'a;
+
+/// Like `pretty`, with the text `decorate` gives printed around each node.
+pub(crate) fn pretty_decorated(root: &Ast, config: &PrettyConfig, decorate: &Decorate) -> String {
+    let mut printer = Printer {
+        config,
+        decorate,
+        path: Vec::new(),
+    };
+    let mut docs = Vec::new();
+    for (i, form) in root.children().iter().enumerate() {
+        if i > 0 {
+            docs.push(Doc::HardLine);
+        }
+        docs.push(printer.doc(form, i));
+    }
+    let mut code = render(&Doc::Concat(docs), config.width);
+    if !code.is_empty()
+        && !code.ends_with('\n')
+        && !root.children().last().is_some_and(ends_bare_char)
+    {
+        code.push('\n');
+    }
+    code
+}
+
+/// Whether the last token printed for `ast` is a bare `#\`, which only
+/// reads back as itself at the very end of a file: followed by a newline it
+/// becomes the newline character.
+fn ends_bare_char(ast: &Ast) -> bool {
+    match ast {
+        Ast::Atom(text) => text == "#\\",
+        Ast::List(_, items) => match items.split_last() {
+            Some((Ast::Missing(_), rest)) => rest.last().is_some_and(ends_bare_char),
+            _ => false,
+        },
+        Ast::Quoted(_, form) => ends_bare_char(form),
+        _ => false,
+    }
+}
+
+struct Printer<'a> {
+    config: &'a PrettyConfig,
+    decorate: &'a Decorate<'a>,
+    /// The path of the node being printed.
+    path: Vec<usize>,
+}
+
+impl Printer<'_> {
+    /// The document for `ast`, the `index`th child of the current node.
+    fn doc(&mut self, ast: &Ast, index: usize) -> Doc {
+        self.path.push(index);
+        let doc = match ast {
+            Ast::List(delimiter, items) => {
+                let mut docs = vec![text(delimiter.open())];
+                docs.push(self.items_doc(items));
+                if items.last().is_some_and(Ast::ends_line) {
+                    docs.push(Doc::HardLine);
+                }
+                if !matches!(items.last(), Some(Ast::Missing(_))) {
+                    docs.push(text(delimiter.close()));
+                }
+                Doc::Group(Box::new(Doc::Concat(docs)))
+            }
+            Ast::Quoted(reader_macro, form) => {
+                Doc::Concat(vec![text(reader_macro.prefix()), self.doc(form, 0)])
+            }
+            Ast::Trivia(Trivia::BlankLine) | Ast::Missing(_) => text(""),
+            _ => text(ast.to_string()),
+        };
+        let doc = match (self.decorate)(&self.path) {
+            Some((before, after)) => Doc::Concat(vec![text(before), doc, text(after)]),
+            None => doc,
+        };
+        self.path.pop();
+        doc
+    }
+
+    /// The items of a list, after its opening delimiter.
+    fn items_doc(&mut self, items: &[Ast]) -> Doc {
+        let Some(first) = items.first() else {
+            return text("");
+        };
+        let rule = match first {
+            Ast::Atom(head) => Some(self.config.rules.get(head).copied()),
+            _ => None,
+        };
+        match rule {
+            // A form with a body: the head and its first arguments, then the
+            // body on indented lines.
+            Some(Some(count)) => {
+                let split = (count + 1).min(items.len());
+                let mut docs = vec![self.doc(first, 0)];
+                for i in 1..split {
+                    docs.push(separator(&items[i - 1], &items[i], text(" ")));
+                    docs.push(self.doc(&items[i], i));
+                }
+                let mut body = Vec::new();
+                for i in split..items.len() {
+                    body.push(separator(&items[i - 1], &items[i], Doc::Line));
+                    body.push(self.doc(&items[i], i));
+                }
+                docs.push(Doc::Nest(self.config.indent, Box::new(Doc::Concat(body))));
+                Doc::Concat(docs)
+            }
+            // A call: the arguments line up under the first one.
+            Some(None) if items.len() > 1 && !items[1].ends_line() => Doc::Concat(vec![
+                self.doc(first, 0),
+                separator(first, &items[1], text(" ")),
+                Doc::Align(Box::new(self.aligned(items, 1))),
+            ]),
+            // Data, or a call with nothing to line up under: the items line up
+            // under the first one.
+            _ => Doc::Align(Box::new(self.aligned(items, 0))),
+        }
+    }
+
+    /// The items from `start` on, one per line if they do not fit on one.
+    fn aligned(&mut self, items: &[Ast], start: usize) -> Doc {
+        let mut docs = vec![self.doc(&items[start], start)];
+        for i in start + 1..items.len() {
+            docs.push(separator(&items[i - 1], &items[i], Doc::Line));
+            docs.push(self.doc(&items[i], i));
+        }
+        Doc::Concat(docs)
+    }
+}
+
+/// What goes between two neighbouring items: nothing before a missing item,
+/// a newline after a line comment and around blank lines, `soft` otherwise.
+fn separator(before: &Ast, after: &Ast, soft: Doc) -> Doc {
+    if matches!(after, Ast::Missing(_)) {
+        text("")
+    } else if before.ends_line() || *after == Ast::Trivia(Trivia::BlankLine) {
+        Doc::HardLine
+    } else {
+        soft
+    }
+}
+
+fn render(doc: &Doc, width: usize) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    // Indentation is written with the next text, so blank lines stay empty.
+    let mut pending_indent = None;
+    let mut stack = vec![(0, Mode::Break, doc)];
+    while let Some((indent, mode, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) if s.is_empty() => {}
+            Doc::Text(s) => {
+                if let Some(indent) = pending_indent.take() {
+                    out.push_str(&" ".repeat(indent));
+                }
+                out.push_str(s);
+                column = match s.rsplit_once('\n') {
+                    Some((_, last)) => text_width(last),
+                    None => column + text_width(s),
+                };
+            }
+            Doc::Line if mode == Mode::Flat => {
+                out.push(' ');
+                column += 1;
+            }
+            Doc::Line | Doc::HardLine => {
+                out.push('\n');
+                pending_indent = Some(indent);
+                column = indent;
+            }
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc))),
+            Doc::Nest(n, doc) => stack.push((indent + n, mode, doc)),
+            Doc::Align(doc) => stack.push((column, mode, doc)),
+            Doc::Group(doc) => {
+                let mode = match mode {
+                    Mode::Flat => Mode::Flat,
+                    _ if fits(width.saturating_sub(column), doc, &stack) => Mode::Flat,
+                    _ => Mode::Break,
+                };
+                stack.push((indent, mode, doc));
+            }
+        }
+    }
+    out
+}
+
+/// Whether `doc` laid out flat, followed by `rest` up to its next line
+/// break, takes at most `width` columns.
+fn fits(width: usize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut remaining = width as isize;
+    let mut stack = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev().map(|&(_, mode, doc)| (mode, doc));
+    while remaining >= 0 {
+        let Some((mode, doc)) = stack.pop().or_else(|| rest.next()) else {
+            return true;
+        };
+        match doc {
+            Doc::Text(s) => remaining -= text_width(s) as isize,
+            Doc::Line if mode == Mode::Flat => remaining -= 1,
+            Doc::Line | Doc::HardLine => return mode == Mode::Break,
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (mode, doc))),
+            Doc::Nest(_, doc) | Doc::Align(doc) | Doc::Group(doc) => stack.push((mode, doc)),
+        }
+    }
+    false
+}
+
+/// The number of columns `s` takes up on a terminal, not counting ANSI
+/// escape sequences.
+fn text_width(s: &str) -> usize {
+    let mut columns = 0;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            columns += 1;
+        }
+    }
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_recovering, tokenize};
+    use proptest::prelude::*;
+    use proptest::strategy::BoxedStrategy;
+
+    fn parse(source: &str) -> Ast {
+        parse_recovering(&tokenize(source)).0
+    }
+
+    fn pretty_with_width(source: &str, width: usize) -> String {
+        let config = PrettyConfig {
+            width,
+            ..PrettyConfig::default()
+        };
+        pretty(&parse(source), &config)
+    }
+
+    #[test]
+    fn test_pretty_breaks_long_forms() {
+        let source = "(define (area shape) (cond ((circle? shape) (* pi (radius shape) (radius shape))) (else (* (width shape) (height shape)))))";
+        assert_eq!(pretty_with_width(source, 200), format!("{}\n", source));
+        assert_eq!(
+            pretty_with_width(source, 40),
+            "\
+(define (area shape)
+  (cond
+    ((circle? shape)
+     (* pi
+        (radius shape)
+        (radius shape)))
+    (else (* (width shape)
+             (height shape)))))
+"
+        );
+        assert_eq!(
+            pretty_with_width("(let ((x 1) (y 2)) (if (< x y) x y))", 14),
+            "\
+(let ((x 1)
+      (y 2))
+  (if (< x y)
+    x
+    y))
+"
+        );
+    }
+
+    #[test]
+    fn test_pretty_keeps_comments_and_blank_lines() {
+        let source = "(a) ; one\n\n\n(defun f (x) ; why\n x) [1 ;; two\n]";
+        assert_eq!(
+            pretty_with_width(source, 80),
+            "(a)\n; one\n\n(defun f (x)\n  ; why\n  x)\n[1\n ;; two\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_pretty_config_from_config() {
+        let config =
+            Config::parse("[format]\nwidth = 100\n[indent]\nmy-define = 1\nif = 2\n").unwrap();
+        let pretty = PrettyConfig::from_config(&config).unwrap();
+        assert_eq!(pretty.width, 100);
+        assert_eq!(pretty.indent, 2);
+        assert_eq!(pretty.rules["my-define"], 1);
+        assert_eq!(pretty.rules["if"], 2);
+        assert_eq!(pretty.rules["let"], 1);
+        let config = Config::parse("[format]\nindent = two\n").unwrap();
+        assert!(PrettyConfig::from_config(&config).is_err());
+    }
+
+    /// Source text of a form made of a few symbols, strings, comments and
+    /// blank lines.
+    fn form_strategy() -> BoxedStrategy<String> {
+        let leaf = prop_oneof![
+            4 => prop::sample::select(vec!["define", "let", "if", "f", "x", "1", "\"s t\""]),
+            1 => prop::sample::select(vec!["#\\a", "#\\(", "#\\;", "#\\space"]),
+            1 => Just("; note\n"),
+            1 => Just("#| block |#"),
+            1 => Just("\n\n"),
+        ]
+        .prop_map(str::to_string);
+        leaf.prop_recursive(3, 40, 6, |inner| {
+            prop_oneof![
+                4 => (
+                    prop::sample::select(vec![("(", ")"), ("[", "]"), ("{", "}")]),
+                    prop::collection::vec(inner.clone(), 0..6),
+                )
+                    .prop_map(|((open, close), items)| format!("{}{}{}", open, items.join(" "), close)),
+                1 => inner.prop_map(|form| format!("'{}", form)),
+            ]
+        })
+        .boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn pretty_roundtrips(
+            forms in prop::collection::vec(form_strategy(), 0..4),
+            width in 0usize..30,
+        ) {
+            let (ast, errors) = parse_recovering(&tokenize(&forms.join("\n")));
+            prop_assume!(errors.is_empty());
+            let config = PrettyConfig { width, ..PrettyConfig::default() };
+            let formatted = pretty(&ast, &config);
+            prop_assert_eq!(&parse(&formatted), &ast);
+            prop_assert_eq!(pretty(&parse(&formatted), &config), formatted);
+        }
+
+        #[test]
+        fn pretty_roundtrips_trailing_bare_char(
+            forms in prop::collection::vec(form_strategy(), 0..3),
+            quoted in any::<bool>(),
+            width in 0usize..30,
+        ) {
+            let tail = if quoted { "'#\\" } else { "#\\" };
+            let source = format!("{} {}", forms.join("\n"), tail);
+            let (ast, errors) = parse_recovering(&tokenize(&source));
+            prop_assume!(errors.is_empty());
+            let config = PrettyConfig { width, ..PrettyConfig::default() };
+            prop_assert_eq!(parse(&pretty(&ast, &config)), ast);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Branch checked out by `init`.
+pub const DEFAULT_BRANCH: &str = "main";
+
+const HEAD_REF_PREFIX: &str = "ref: refs/heads/";
+
+/// What `.trefoil/HEAD` points at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Head {
+    /// `ref: refs/heads/<name>`; commits advance the branch.
+    Branch(String),
+    /// A bare commit id, as written by `checkout <id>` and by older repositories.
+    Detached(String),
+}
+
+pub fn read_head(dir: &Path) -> Result<Head, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(dir.join("HEAD"))?;
+    let contents = contents.trim();
+    match contents.strip_prefix(HEAD_REF_PREFIX) {
+        Some(name) => Ok(Head::Branch(name.to_string())),
+        None => Ok(Head::Detached(contents.to_string())),
+    }
+}
+
+pub fn write_head(head: &Head, dir: &Path) -> Result<(), Box<dyn Error>> {
+    let contents = match head {
+        Head::Branch(name) => format!("{}{}", HEAD_REF_PREFIX, name),
+        Head::Detached(id) => id.clone(),
+    };
+    std::fs::write(dir.join("HEAD"), contents)?;
+    Ok(())
+}
+
+/// Where branch `name` is stored. Fails for invalid names, so that none can
+/// reach outside `refs/heads`.
+fn branch_path(name: &str, dir: &Path) -> Result<PathBuf, String> {
+    validate_branch_name(name)?;
+    Ok(dir.join("refs").join("heads").join(name))
+}
+
+/// Branch names are `/`-separated segments of letters, digits, `-`, `_` and `.`,
+/// where no segment may be empty or start with a dot.
+pub fn validate_branch_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.split('/').all(|segment| {
+            !segment.is_empty()
+                && !segment.starts_with('.')
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a valid branch name.", name))
+    }
+}
+
+pub fn branch_exists(name: &str, dir: &Path) -> bool {
+    branch_path(name, dir).is_ok_and(|path| path.is_file())
+}
+
+pub fn read_branch(name: &str, dir: &Path) -> Result<String, Box<dyn Error>> {
+    let path = branch_path(name, dir)?;
+    if !path.is_file() {
+        return Err(format!("Branch '{}' not found.", name).into());
+    }
+    Ok(std::fs::read_to_string(path)?.trim().to_string())
+}
+
+pub fn write_branch(name: &str, id: &str, dir: &Path) -> Result<(), Box<dyn Error>> {
+    let path = branch_path(name, dir)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, id)?;
+    Ok(())
+}
+
+pub fn delete_branch(name: &str, dir: &Path) -> Result<(), Box<dyn Error>> {
+    let path = branch_path(name, dir)?;
+    if !path.is_file() {
+        return Err(format!("Branch '{}' not found.", name).into());
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// All branches with the commit they point at, sorted by name.
+pub fn list_branches(dir: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    fn walk(
+        base: &Path,
+        current: &Path,
+        out: &mut Vec<(String, String)>,
+    ) -> Result<(), Box<dyn Error>> {
+        for entry in std::fs::read_dir(current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                walk(base, &path, out)?;
+            } else if let Ok(relative) = path.strip_prefix(base) {
+                let name = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let id = std::fs::read_to_string(&path)?.trim().to_string();
+                out.push((name, id));
+            }
+        }
+        Ok(())
+    }
+
+    let heads = dir.join("refs").join("heads");
+    let mut branches = Vec::new();
+    if heads.is_dir() {
+        walk(&heads, &heads, &mut branches)?;
+    }
+    branches.sort();
+    Ok(branches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_validate_branch_name() {
+        assert!(validate_branch_name("main").is_ok());
+        assert!(validate_branch_name("feature/parser-v2").is_ok());
+        assert!(validate_branch_name("").is_err());
+        assert!(validate_branch_name("a//b").is_err());
+        assert!(validate_branch_name("../escape").is_err());
+        assert!(validate_branch_name("has space").is_err());
+    }
+
+    #[test]
+    fn test_branch_names_cannot_escape_refs() {
+        let temp = TempDir::new("refs");
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("refs").join("heads")).unwrap();
+        std::fs::write(dir.join("refs").join("x"), "keep").unwrap();
+        assert!(delete_branch("../x", dir).is_err());
+        assert!(read_branch("../x", dir).is_err());
+        assert!(!branch_exists("../x", dir));
+        assert!(dir.join("refs").join("x").is_file());
+    }
+}
@@ -0,0 +1,450 @@
+use crate::apply::apply_instruction;
+use crate::ast::{Ast, Delimiter, ReaderMacro, Trivia};
+use crate::diff::lcs;
+use crate::instruction::Instruction;
+use crate::locator::locate;
+use crate::pretty::{PrettyConfig, pretty_decorated};
+use std::collections::HashMap;
+
+/// How changed sub-expressions stand out from the code around them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Highlight {
+    /// `{+inserted+}` and `[-deleted-]`, readable anywhere.
+    Markers,
+    /// Green for inserted and red for deleted, for terminals.
+    Colors,
+}
+
+/// A top-level form that changed, printed with its changes highlighted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// The locator of the form in the new file, or in the old file if it was
+    /// deleted.
+    pub locator: String,
+    pub text: String,
+}
+
+/// The top-level forms of `old` that `instructions` change, each printed as
+/// it reads after the change, with what was deleted shown where it was.
+pub fn hunks(
+    old: &Ast,
+    instructions: &[Instruction],
+    config: &PrettyConfig,
+    highlight: Highlight,
+) -> Vec<Hunk> {
+    let mut root = Node::new(old, Mark::Kept);
+    if instructions.iter().any(|i| apply(&mut root, i).is_none()) {
+        // The instructions do not come from `diff_ast`; show whole forms.
+        root = whole_forms(old, instructions);
+    }
+
+    let new = root.live();
+    let mut hunks = Vec::new();
+    let (mut old_index, mut new_index) = (0, 0);
+    for node in &root.children {
+        let mut marks = HashMap::new();
+        let form = node.display(&mut vec![0], &mut marks);
+        let locator = match node.mark {
+            Mark::Deleted => locate(old, &[old_index]),
+            _ => locate(&new, &[new_index]),
+        };
+        old_index += usize::from(node.mark != Mark::Inserted);
+        new_index += usize::from(node.is_live());
+        if marks.is_empty() {
+            continue;
+        }
+        let decorate = |path: &[usize]| marks.get(path).map(|mark| decoration(mark, highlight));
+        let text = pretty_decorated(&Ast::list(vec![form]), config, &decorate);
+        hunks.push(Hunk {
+            locator,
+            text: text.trim_end().to_string(),
+        });
+    }
+    hunks
+}
+
+/// What happened to a node on the way from the old tree to the new one.
+#[derive(Debug, Clone, PartialEq)]
+enum Mark {
+    Kept,
+    Inserted,
+    /// A node that is gone, kept in place to be shown.
+    Deleted,
+    /// A leaf or form that now reads differently; holds what it read before.
+    Replaced(Ast),
+}
+
+#[derive(Debug, Clone)]
+enum Shape {
+    Leaf(Ast),
+    List(Delimiter),
+    Quoted(ReaderMacro),
+}
+
+/// A node of the old tree with the instructions applied so far, where deleted
+/// children stay behind as ghosts. Instruction paths count live children only.
+#[derive(Debug, Clone)]
+struct Node {
+    shape: Shape,
+    mark: Mark,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn new(ast: &Ast, mark: Mark) -> Node {
+        let (shape, children) = match ast {
+            Ast::List(delimiter, items) => (
+                Shape::List(*delimiter),
+                items
+                    .iter()
+                    .map(|item| Node::new(item, Mark::Kept))
+                    .collect(),
+            ),
+            Ast::Quoted(reader_macro, form) => (
+                Shape::Quoted(*reader_macro),
+                vec![Node::new(form, Mark::Kept)],
+            ),
+            _ => (Shape::Leaf(ast.clone()), Vec::new()),
+        };
+        Node {
+            shape,
+            mark,
+            children,
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        self.mark != Mark::Deleted
+    }
+
+    /// The node as it reads now.
+    fn live(&self) -> Ast {
+        let children = self.children.iter().filter(|c| c.is_live()).map(Node::live);
+        self.build(children.collect())
+    }
+
+    /// The node as it read in the old tree.
+    fn old(&self) -> Ast {
+        match &self.mark {
+            Mark::Replaced(old) => old.clone(),
+            _ => {
+                let children = self.children.iter().filter(|c| c.mark != Mark::Inserted);
+                self.build(children.map(Node::old).collect())
+            }
+        }
+    }
+
+    fn build(&self, mut children: Vec<Ast>) -> Ast {
+        match &self.shape {
+            Shape::Leaf(ast) => ast.clone(),
+            Shape::List(delimiter) => Ast::List(*delimiter, children),
+            Shape::Quoted(reader_macro) => match children.pop() {
+                Some(form) => Ast::Quoted(*reader_macro, Box::new(form)),
+                None => Ast::Missing(reader_macro.prefix().to_string()),
+            },
+        }
+    }
+
+    /// The position in `children` of the live child `index`; one past the
+    /// end for the index just after the last live child.
+    fn position(&self, index: usize) -> Option<usize> {
+        let live = self
+            .children
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.is_live());
+        match live.map(|(i, _)| i).nth(index) {
+            Some(i) => Some(i),
+            None if index == self.children.iter().filter(|c| c.is_live()).count() => {
+                Some(self.children.len())
+            }
+            None => None,
+        }
+    }
+
+    fn get_mut(&mut self, path: &[usize]) -> Option<&mut Node> {
+        let mut node = self;
+        for &index in path {
+            let i = node.position(index)?;
+            node = node.children.get_mut(i)?;
+        }
+        Some(node)
+    }
+
+    /// Takes the live child `index` out, leaving a ghost of what it was in
+    /// the old tree unless it did not exist there.
+    fn remove(&mut self, index: usize) -> Option<Node> {
+        let i = self.position(index).filter(|&i| i < self.children.len())?;
+        if self.children[i].mark == Mark::Inserted {
+            return Some(self.children.remove(i));
+        }
+        let ghost = Node::new(&self.children[i].old(), Mark::Deleted);
+        Some(std::mem::replace(&mut self.children[i], ghost))
+    }
+
+    fn insert(&mut self, index: usize, node: Node) -> Option<()> {
+        if matches!(self.shape, Shape::Leaf(_)) {
+            return None;
+        }
+        let i = self.position(index)?;
+        self.children.insert(i, node);
+        Some(())
+    }
+
+    /// The node as it is shown, with the marks of changed nodes recorded by
+    /// their path in it. Nothing under a changed node is marked.
+    fn display(&self, path: &mut Vec<usize>, marks: &mut HashMap<Vec<usize>, Mark>) -> Ast {
+        if matches!(&self.shape, Shape::Leaf(Ast::Trivia(Trivia::BlankLine))) {
+            // There is nothing to highlight.
+            return Ast::Trivia(Trivia::BlankLine);
+        }
+        match &self.mark {
+            Mark::Kept => {}
+            Mark::Deleted => {
+                marks.insert(path.clone(), Mark::Deleted);
+                return self.old();
+            }
+            mark => {
+                marks.insert(path.clone(), mark.clone());
+                return self.live();
+            }
+        }
+        let mut children = Vec::new();
+        for (i, child) in self.children.iter().enumerate() {
+            path.push(i);
+            children.push(child.display(path, marks));
+            path.pop();
+        }
+        self.build(children)
+    }
+}
+
+/// Applies `instruction` to `root`, or `None` if it does not fit.
+fn apply(root: &mut Node, instruction: &Instruction) -> Option<()> {
+    match instruction {
+        Instruction::Insert { path, index, node } => root
+            .get_mut(path)?
+            .insert(*index, Node::new(node, Mark::Inserted)),
+        Instruction::Delete { path, index, .. } => root.get_mut(path)?.remove(*index).map(|_| ()),
+        Instruction::Update {
+            path, new_value, ..
+        } => {
+            let node = root.get_mut(path)?;
+            // Resolve the new text against the leaf alone, as `apply` would.
+            let update = Instruction::Update {
+                path: vec![0],
+                new_value: new_value.clone(),
+                old_value: None,
+            };
+            let leaf = apply_instruction(Ast::list(vec![node.live()]), update).ok()?;
+            replace(node, leaf.children().first()?);
+            Some(())
+        }
+        Instruction::Replace {
+            path, node: new, ..
+        } => {
+            if path.is_empty() {
+                // Nothing of the old root is kept.
+                let mut children: Vec<Node> = root
+                    .children
+                    .iter()
+                    .filter(|c| c.is_live())
+                    .map(|c| Node::new(&c.old(), Mark::Deleted))
+                    .collect();
+                children.extend(new.children().iter().map(|c| Node::new(c, Mark::Inserted)));
+                *root = Node {
+                    children,
+                    ..Node::new(new, Mark::Kept)
+                };
+                return Some(());
+            }
+            let (index, parent_path) = path.split_last()?;
+            let parent = root.get_mut(parent_path)?;
+            let i = parent
+                .position(*index)
+                .filter(|&i| i < parent.children.len())?;
+            let node = &parent.children[i];
+            if node.mark == Mark::Kept
+                && matches!(parent.shape, Shape::List(_))
+                && !(is_leaf(&node.old()) && is_leaf(new))
+            {
+                // A form, or a comment, reads better deleted and inserted whole.
+                let ghost = Node::new(&node.old(), Mark::Deleted);
+                parent.children[i] = Node::new(new, Mark::Inserted);
+                parent.children.insert(i, ghost);
+            } else {
+                replace(&mut parent.children[i], new);
+            }
+            Some(())
+        }
+        Instruction::Move {
+            from_path,
+            from_index,
+            to_path,
+            to_index,
+        } => {
+            let node = root.get_mut(from_path)?.remove(*from_index)?;
+            let moved = Node::new(&node.live(), Mark::Inserted);
+            root.get_mut(to_path)?.insert(*to_index, moved)
+        }
+    }
+}
+
+/// Makes `node` read as `new`, remembering what it read before.
+fn replace(node: &mut Node, new: &Ast) {
+    let mark = match &node.mark {
+        Mark::Kept => Mark::Replaced(node.old()),
+        mark => mark.clone(),
+    };
+    *node = Node::new(new, mark);
+}
+
+fn is_leaf(ast: &Ast) -> bool {
+    matches!(ast, Ast::Atom(_) | Ast::Literal(..) | Ast::Str(_))
+}
+
+/// The old and new top-level forms of a file, with the forms both share kept
+/// and the others shown as deleted and inserted whole.
+fn whole_forms(old: &Ast, instructions: &[Instruction]) -> Node {
+    let mut new = old.clone();
+    for instruction in instructions {
+        // Instructions that fail leave the tree as it was.
+        new = apply_instruction(new.clone(), instruction.clone()).unwrap_or(new);
+    }
+    let (old_forms, new_forms) = (old.children(), new.children());
+    let mut children = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (oi, nj) in lcs(old_forms, new_forms)
+        .into_iter()
+        .chain([(old_forms.len(), new_forms.len())])
+    {
+        children.extend(old_forms[i..oi].iter().map(|f| Node::new(f, Mark::Deleted)));
+        children.extend(
+            new_forms[j..nj]
+                .iter()
+                .map(|f| Node::new(f, Mark::Inserted)),
+        );
+        if let Some(form) = old_forms.get(oi) {
+            children.push(Node::new(form, Mark::Kept));
+        }
+        (i, j) = (oi + 1, nj + 1);
+    }
+    Node {
+        children,
+        ..Node::new(&Ast::list(vec![]), Mark::Kept)
+    }
+}
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// The text printed before and after a changed node.
+fn decoration(mark: &Mark, highlight: Highlight) -> (String, String) {
+    match (mark, highlight) {
+        (Mark::Inserted, Highlight::Markers) => ("{+".to_string(), "+}".to_string()),
+        (Mark::Deleted, Highlight::Markers) => ("[-".to_string(), "-]".to_string()),
+        (Mark::Replaced(old), Highlight::Markers) => (format!("[-{}-]{{+", old), "+}".to_string()),
+        (Mark::Inserted, Highlight::Colors) => (GREEN.to_string(), RESET.to_string()),
+        (Mark::Deleted, Highlight::Colors) => (RED.to_string(), RESET.to_string()),
+        (Mark::Replaced(old), Highlight::Colors) => (
+            format!("{}{}{} {}", RED, old, RESET, GREEN),
+            RESET.to_string(),
+        ),
+        (Mark::Kept, _) => (String::new(), String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{DiffAlgorithm, diff, diff_ast};
+    use crate::parser::{parse, tokenize};
+    use proptest::prelude::*;
+
+    fn rendered(old: &str, new: &str) -> Vec<String> {
+        let (old, new) = (
+            parse(&tokenize(old)).unwrap(),
+            parse(&tokenize(new)).unwrap(),
+        );
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        hunks(
+            &old,
+            &instructions,
+            &PrettyConfig::default(),
+            Highlight::Markers,
+        )
+        .into_iter()
+        .map(|hunk| hunk.text)
+        .collect()
+    }
+
+    #[test]
+    fn test_hunks_show_changes_in_their_form() {
+        assert_eq!(
+            rendered(
+                "(define x 10)\n(define (f a) (+ a 1))",
+                "(define x 10)\n(define (f a) (* a 2))"
+            ),
+            vec!["(define (f a) ([-+-]{+*+} a [-1-]{+2+}))"]
+        );
+        assert_eq!(
+            rendered("(list 1 2 3)", "(list 1 3 4)"),
+            vec!["(list 1 [-2-] 3 {+4+})"]
+        );
+        assert_eq!(rendered("(a)\n(b)", "(b)\n(c)"), vec!["[-(a)-]", "{+(c)+}"]);
+        assert_eq!(
+            rendered("(f (g 1))\n\n; old", "(f 2)\n(h)"),
+            vec!["(f [-(g 1)-] {+2+})", "{+(h)+}", "[-; old-]"]
+        );
+    }
+
+    #[test]
+    fn test_hunks_are_located() {
+        let old = parse(&tokenize("(a)\n(b)\n; c\n(define c)")).unwrap();
+        let new = parse(&tokenize("(b)\n; the c\n(define c 1)")).unwrap();
+        let instructions = diff_ast(&old, &new, &mut vec![]);
+        let hunks = hunks(
+            &old,
+            &instructions,
+            &PrettyConfig::default(),
+            Highlight::Colors,
+        );
+        let locators: Vec<_> = hunks.iter().map(|h| h.locator.as_str()).collect();
+        assert_eq!(locators, vec!["(a)", "comment", "comment", "(define c)"]);
+        assert_eq!(hunks[3].text, "(define c \x1b[32m1\x1b[0m)");
+    }
+
+    fn form_strategy() -> impl Strategy<Value = Ast> {
+        let leaf = prop_oneof![
+            "[a-d]".prop_map(Ast::Atom),
+            (0..4u8).prop_map(|n| parse(&tokenize(&n.to_string())).unwrap().children()[0].clone()),
+        ];
+        leaf.prop_recursive(3, 16, 4, |inner| {
+            prop::collection::vec(inner, 0..4).prop_map(Ast::list)
+        })
+    }
+
+    proptest! {
+        /// Without highlighting, what a hunk shows as kept or inserted is the
+        /// new form and what it shows as kept or deleted is the old one.
+        #[test]
+        fn hunks_show_both_sides(
+            old in prop::collection::vec(form_strategy(), 0..4),
+            new in prop::collection::vec(form_strategy(), 0..4),
+            top_down in any::<bool>(),
+        ) {
+            let (old, new) = (Ast::list(old), Ast::list(new));
+            let algorithm = if top_down { DiffAlgorithm::TopDown } else { DiffAlgorithm::Fast };
+            let mut root = Node::new(&old, Mark::Kept);
+            for instruction in &diff(&old, &new, algorithm) {
+                let applied = apply(&mut root, instruction).is_some();
+                // Only `diff_ast` output is sure to fit; other scripts may fall back.
+                prop_assume!(applied || !top_down);
+                prop_assert!(applied, "{} did not apply", instruction);
+            }
+            prop_assert_eq!(root.live(), new);
+            prop_assert_eq!(root.old(), old);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use crate::instruction::Instruction;
+use std::fmt::{Display, Formatter};
+
+/// Why two edits to a common ancestor could not both be kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the same node in different ways.
+    BothChanged,
+    /// One side deleted or replaced a subtree the other side edited.
+    RemovedWhileEdited,
+    /// The rebased instruction did not apply to the merged tree.
+    Inapplicable,
+}
+
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    /// Instruction from `diff_ast(base, ours)`, if one was involved.
+    pub ours: Option<Instruction>,
+    /// Instruction from `diff_ast(base, theirs)`.
+    pub theirs: Instruction,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.kind {
+            ConflictKind::BothChanged => "both sides changed the same node",
+            ConflictKind::RemovedWhileEdited => "one side removed a node the other side edited",
+            ConflictKind::Inapplicable => "their change no longer applies",
+        };
+        write!(f, "{}", reason)?;
+        if let Some(ours) = &self.ours {
+            write!(f, "\n  ours:   {}", ours)?;
+        }
+        write!(f, "\n  theirs: {}", self.theirs)
+    }
+}
+
+/// Transforms `theirs` so it applies after `ours`, where both were computed
+/// against the same tree.
+///
+/// This is operational transformation over paths: each of their instructions
+/// is shifted past every one of ours, and ours are shifted past it in turn
+/// so they line up with the next one. When both sides insert at the same
+/// index, ours comes first. Instructions present on both sides are applied
+/// once.
+pub fn rebase(
+    theirs: &[Instruction],
+    ours: &[Instruction],
+) -> Result<Vec<Instruction>, Vec<Conflict>> {
+    // Ours, paired with the original instruction for conflict reports, in the
+    // coordinates of the base plus the rebased prefix of theirs.
+    let mut current: Vec<(&Instruction, Instruction)> =
+        ours.iter().map(|o| (o, o.clone())).collect();
+    let mut rebased = Vec::new();
+    let mut conflicts = Vec::new();
+
+    for original in theirs {
+        let mut theirs = Some(original.clone());
+        let mut next = Vec::with_capacity(current.len());
+        for (our_original, ours) in current {
+            let Some(t) = theirs.take() else {
+                next.push((our_original, ours));
+                continue;
+            };
+            if let Some(kind) = conflict(&ours, &t) {
+                conflicts.push(Conflict {
+                    kind,
+                    ours: Some(our_original.clone()),
+                    theirs: original.clone(),
+                });
+                next.push((our_original, ours));
+                continue;
+            }
+            theirs = transform(&t, &ours, true);
+            if let Some(ours) = transform(&ours, &t, false) {
+                next.push((our_original, ours));
+            }
+        }
+        current = next;
+        rebased.extend(theirs);
+    }
+
+    if conflicts.is_empty() {
+        Ok(rebased)
+    } else {
+        Err(conflicts)
+    }
+}
+
+/// Paths of the nodes an instruction overwrites or removes outright.
+fn removed(instruction: &Instruction) -> Option<Vec<usize>> {
+    match instruction {
+        Instruction::Delete { path, index, .. } => Some(child(path, *index)),
+        Instruction::Update { path, .. } | Instruction::Replace { path, .. } => Some(path.clone()),
+        Instruction::Insert { .. } | Instruction::Move { .. } => None,
+    }
+}
+
+/// Paths of the nodes an instruction needs to still exist.
+fn touched(instruction: &Instruction) -> Vec<Vec<usize>> {
+    match instruction {
+        Instruction::Insert { path, .. } => vec![path.clone()],
+        Instruction::Delete { path, index, .. } => vec![child(path, *index)],
+        Instruction::Update { path, .. } | Instruction::Replace { path, .. } => vec![path.clone()],
+        Instruction::Move {
+            from_path,
+            from_index,
+            to_path,
+            ..
+        } => vec![
+            child(from_path, *from_index),
+            unremove_path(to_path, from_path, *from_index),
+        ],
+    }
+}
+
+/// Whether two instructions against the same tree cannot both be applied.
+fn conflict(a: &Instruction, b: &Instruction) -> Option<ConflictKind> {
+    if a == b {
+        return None;
+    }
+    if let (
+        Instruction::Move {
+            from_path: a_path,
+            from_index: a_index,
+            ..
+        },
+        Instruction::Move {
+            from_path: b_path,
+            from_index: b_index,
+            ..
+        },
+    ) = (a, b)
+        && a_path == b_path
+        && a_index == b_index
+    {
+        return Some(ConflictKind::BothChanged);
+    }
+    for (x, y) in [(a, b), (b, a)] {
+        let Some(removed) = removed(x) else { continue };
+        for touched in touched(y) {
+            if touched.starts_with(&removed) {
+                let overwrites = |i: &Instruction| {
+                    matches!(i, Instruction::Update { .. } | Instruction::Replace { .. })
+                };
+                return Some(if touched == removed && overwrites(x) && overwrites(y) {
+                    ConflictKind::BothChanged
+                } else {
+                    ConflictKind::RemovedWhileEdited
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Rewrites `a` to apply after `b`, where both were computed against the same
+/// tree and do not conflict. Returns `None` if `b` already did what `a` does.
+/// On equal insertion indices, `a` goes after `b` if `after_on_tie` is set.
+fn transform(a: &Instruction, b: &Instruction, after_on_tie: bool) -> Option<Instruction> {
+    if a == b {
+        return None;
+    }
+    Some(match a {
+        Instruction::Insert { path, index, node } => {
+            let (path, index) = map_slot(path, *index, b, after_on_tie)?;
+            Instruction::Insert {
+                path,
+                index,
+                node: node.clone(),
+            }
+        }
+        Instruction::Delete { path, index, node } => {
+            let (path, index) = split(map_path(&child(path, *index), b)?);
+            Instruction::Delete {
+                path,
+                index,
+                node: node.clone(),
+            }
+        }
+        Instruction::Update {
+            path,
+            new_value,
+            old_value,
+        } => Instruction::Update {
+            path: map_path(path, b)?,
+            new_value: new_value.clone(),
+            old_value: old_value.clone(),
+        },
+        Instruction::Replace { path, node, old } => Instruction::Replace {
+            path: map_path(path, b)?,
+            node: node.clone(),
+            old: old.clone(),
+        },
+        Instruction::Move {
+            from_path,
+            from_index,
+            to_path,
+            to_index,
+        } => {
+            // Work with the destination as a slot in the tree before the
+            // removal, map both ends, then remove again.
+            let (dest_path, dest_index) = unremove_slot(to_path, *to_index, from_path, *from_index);
+            let (from_path, from_index) = split(map_path(&child(from_path, *from_index), b)?);
+            let (dest_path, dest_index) = map_slot(&dest_path, dest_index, b, after_on_tie)?;
+            let (to_path, to_index) = remove_slot(&dest_path, dest_index, &from_path, from_index);
+            Instruction::Move {
+                from_path,
+                from_index,
+                to_path,
+                to_index,
+            }
+        }
+    })
+}
+
+/// Where the node at `path` ends up after `by` is applied, or `None` if `by`
+/// removes it.
+fn map_path(path: &[usize], by: &Instruction) -> Option<Vec<usize>> {
+    match by {
+        Instruction::Insert {
+            path: list, index, ..
+        } => shift(path, list, |i| Some(if i >= *index { i + 1 } else { i })),
+        Instruction::Delete {
+            path: list, index, ..
+        } => shift(path, list, |i| match i.cmp(index) {
+            std::cmp::Ordering::Less => Some(i),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some(i - 1),
+        }),
+        Instruction::Update { .. } | Instruction::Replace { .. } => Some(path.to_vec()),
+        Instruction::Move {
+            from_path,
+            from_index,
+            to_path,
+            to_index,
+        } => {
+            let source = child(from_path, *from_index);
+            if path.starts_with(&source) {
+                let mut moved = child(to_path, *to_index);
+                moved.extend_from_slice(&path[source.len()..]);
+                return Some(moved);
+            }
+            let removed = remove_path(path, from_path, *from_index);
+            shift(&removed, to_path, |i| {
+                Some(if i >= *to_index { i + 1 } else { i })
+            })
+        }
+    }
+}
+
+/// Where the insertion slot `index` of the list at `path` ends up after `by`.
+fn map_slot(
+    path: &[usize],
+    index: usize,
+    by: &Instruction,
+    after_on_tie: bool,
+) -> Option<(Vec<usize>, usize)> {
+    let inserted = |list: &[usize], at: usize, index: usize| {
+        if list == path && (at < index || (at == index && after_on_tie)) {
+            index + 1
+        } else {
+            index
+        }
+    };
+    match by {
+        Instruction::Insert {
+            path: list,
+            index: at,
+            ..
+        } => Some((map_path(path, by)?, inserted(list, *at, index))),
+        Instruction::Delete {
+            path: list,
+            index: at,
+            ..
+        } => {
+            let index = if list == path && *at < index {
+                index - 1
+            } else {
+                index
+            };
+            Some((map_path(path, by)?, index))
+        }
+        Instruction::Update { .. } | Instruction::Replace { .. } => Some((path.to_vec(), index)),
+        Instruction::Move {
+            from_path,
+            from_index,
+            to_path,
+            to_index,
+        } => {
+            if path.starts_with(&child(from_path, *from_index)) {
+                return Some((map_path(path, by)?, index));
+            }
+            let (path, index) = remove_slot(path, index, from_path, *from_index);
+            let index = if to_path == &path
+                && (*to_index < index || (*to_index == index && after_on_tie))
+            {
+                index + 1
+            } else {
+                index
+            };
+            let path = shift(&path, to_path, |i| {
+                Some(if i >= *to_index { i + 1 } else { i })
+            })?;
+            Some((path, index))
+        }
+    }
+}
+
+/// Applies `f` to the index `path` takes within the list at `list`, if
+/// `path` lies strictly below it.
+fn shift(
+    path: &[usize],
+    list: &[usize],
+    f: impl FnOnce(usize) -> Option<usize>,
+) -> Option<Vec<usize>> {
+    if path.len() > list.len() && path.starts_with(list) {
+        let mut shifted = path.to_vec();
+        shifted[list.len()] = f(path[list.len()])?;
+        Some(shifted)
+    } else {
+        Some(path.to_vec())
+    }
+}
+
+/// `path` after removing the child `index` of `list`, for a `path` outside
+/// that child.
+fn remove_path(path: &[usize], list: &[usize], index: usize) -> Vec<usize> {
+    shift(path, list, |i| Some(if i > index { i - 1 } else { i })).unwrap_or_default()
+}
+
+/// `path` before removing the child `index` of `list`.
+fn unremove_path(path: &[usize], list: &[usize], index: usize) -> Vec<usize> {
+    shift(path, list, |i| Some(if i >= index { i + 1 } else { i })).unwrap_or_default()
+}
+
+/// Slot `index` of `path` after removing the child `removed` of `list`.
+fn remove_slot(
+    path: &[usize],
+    index: usize,
+    list: &[usize],
+    removed: usize,
+) -> (Vec<usize>, usize) {
+    let index = if path == list && index > removed {
+        index - 1
+    } else {
+        index
+    };
+    (remove_path(path, list, removed), index)
+}
+
+/// Slot `index` of `path` before removing the child `removed` of `list`.
+fn unremove_slot(
+    path: &[usize],
+    index: usize,
+    list: &[usize],
+    removed: usize,
+) -> (Vec<usize>, usize) {
+    let path = unremove_path(path, list, removed);
+    let index = if path == list && index > removed {
+        index + 1
+    } else {
+        index
+    };
+    (path, index)
+}
+
+fn child(path: &[usize], index: usize) -> Vec<usize> {
+    let mut child = path.to_vec();
+    child.push(index);
+    child
+}
+
+/// Splits a node path into its parent list and index. Only called with
+/// paths of list children, which are never empty.
+fn split(mut path: Vec<usize>) -> (Vec<usize>, usize) {
+    let index = path.pop().expect("child path is never empty");
+    (path, index)
+}
@@ -1,16 +1,326 @@
-use crate::apply::apply_instruction;
+use crate::apply::{ApplyError, apply_all};
 use crate::ast::Ast;
-use crate::instruction::Instruction;
+use crate::diff::{DiffAlgorithm, diff, similarity};
+use crate::instruction::{Instruction, invert_all};
+use crate::layout::{Layout, LayoutDelta};
+use crate::parser::reclassify_atoms;
+use crate::refs::{Head, read_branch, read_head, write_branch, write_head};
+use chrono::{DateTime, FixedOffset, Local};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::path::Path;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Number of hex digits shown for abbreviated commit ids.
+pub const SHORT_ID_LEN: usize = 7;
+
+/// Who wrote or recorded a commit.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
+
+/// Everything about a commit besides its place in history and its changes.
+///
+/// Commits written before these fields existed load with the defaults, and
+/// default fields are left out of the serialized form so their ids still match.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitMetadata {
+    /// Seconds since the UNIX epoch; `0` on commits that predate real timestamps.
+    pub timestamp: u64,
+    /// The committer's UTC offset in minutes east of UTC when the commit was made.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub tz_offset: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<Signature>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub committer: Option<Signature>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+impl CommitMetadata {
+    /// Unsigned metadata for a commit made now, in the local time zone.
+    pub fn now(message: String) -> Self {
+        let now = Local::now();
+        CommitMetadata {
+            timestamp: now.timestamp().max(0) as u64,
+            tz_offset: now.offset().local_minus_utc() / 60,
+            author: None,
+            committer: None,
+            message,
+        }
+    }
+
+    /// The commit time as `Sat Oct 18 14:03:11 2026 +0200`, in the time zone
+    /// it was made in. `None` for commits without a real timestamp.
+    pub fn date(&self) -> Option<String> {
+        if self.timestamp == 0 {
+            return None;
+        }
+        let offset = FixedOffset::east_opt(self.tz_offset * 60)?;
+        let time = DateTime::from_timestamp(i64::try_from(self.timestamp).ok()?, 0)?;
+        Some(
+            time.with_timezone(&offset)
+                .format("%a %b %-d %H:%M:%S %Y %z")
+                .to_string(),
+        )
+    }
+}
+
+fn is_zero(value: &i32) -> bool {
+    *value == 0
+}
+
+/// The file every commit changed before trefoil tracked whole directories.
+pub const LEGACY_FILE: &str = "code.lisp";
+
+/// A file as of some commit.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileState {
+    pub ast: Ast,
+    #[serde(default)]
+    pub layout: Layout,
+}
+
+impl Default for FileState {
+    fn default() -> Self {
+        FileState {
+            ast: Ast::list(vec![]),
+            layout: Layout::default(),
+        }
+    }
+}
+
+/// Every file of a commit, keyed by its `/`-separated path relative to the
+/// repository root.
+pub type Tree = BTreeMap<String, FileState>;
+
+/// How a commit changes the AST and the layout of one file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FileEdit {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instructions: Vec<Instruction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<LayoutDelta>,
+}
+
+impl FileEdit {
+    /// The edit turning `old` into `new`.
+    pub fn between(old: &FileState, new: &FileState, algorithm: DiffAlgorithm) -> Self {
+        FileEdit {
+            instructions: diff(&old.ast, &new.ast, algorithm),
+            layout: LayoutDelta::between(&old.layout, &new.layout),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty() && self.layout.is_none()
+    }
+}
+
+/// What a commit does to the file at one path.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FileChange {
+    /// A new file, built up from an empty one.
+    Added(FileEdit),
+    Modified(FileEdit),
+    Removed,
+    /// The file at `from`, moved here and edited.
+    Renamed {
+        from: String,
+        edit: FileEdit,
+    },
+}
+
+impl FileChange {
+    pub fn edit(&self) -> Option<&FileEdit> {
+        match self {
+            FileChange::Added(edit) | FileChange::Modified(edit) => Some(edit),
+            FileChange::Renamed { edit, .. } => Some(edit),
+            FileChange::Removed => None,
+        }
+    }
+}
+
+impl FileChange {
+    /// One line saying what happened to the file now at `path`.
+    pub fn summary(&self, path: &str) -> String {
+        match self {
+            FileChange::Added(_) => format!("added {}", path),
+            FileChange::Modified(_) => format!("modified {}", path),
+            FileChange::Removed => format!("removed {}", path),
+            FileChange::Renamed { from, .. } => format!("renamed {} -> {}", from, path),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Commit {
-    pub id: u64,
-    pub parent_id: Option<u64>,
+    /// Hex SHA-256 of the commit's canonical form; see `Commit::compute_id`.
+    pub id: String,
+    /// Parent commits. The commit's changes transform the files of the first
+    /// parent into its own; a merge commit has further parents.
+    pub parents: Vec<String>,
+    /// Changes to `LEGACY_FILE`, on commits without `files`.
     pub instructions: Vec<Instruction>,
-    pub timestamp: u64,
+    /// How the layout of `LEGACY_FILE` changed, on commits without `files`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<LayoutDelta>,
+    /// What the commit does to each file it touches. `None` on commits made
+    /// before trefoil tracked directories; see `Commit::changes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<BTreeMap<String, FileChange>>,
+    /// The files the commit removes, as they were, so that it can be undone.
+    /// `None` if it removes none, or was made before they were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed_files: Option<Tree>,
+    #[serde(flatten)]
+    pub metadata: CommitMetadata,
+}
+
+/// Everything a commit id covers: the commit minus the id itself.
+#[derive(Serialize)]
+struct CommitContent<'a> {
+    parents: &'a [String],
+    instructions: &'a [Instruction],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layout: &'a Option<LayoutDelta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: &'a Option<BTreeMap<String, FileChange>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed_files: &'a Option<Tree>,
+    #[serde(flatten)]
+    metadata: &'a CommitMetadata,
+}
+
+impl Commit {
+    /// Builds a commit and derives its id from the contents.
+    pub fn new(
+        parents: Vec<String>,
+        instructions: Vec<Instruction>,
+        metadata: CommitMetadata,
+    ) -> Self {
+        let mut commit = Commit {
+            id: String::new(),
+            parents,
+            instructions,
+            layout: None,
+            files: None,
+            removed_files: None,
+            metadata,
+        };
+        commit.id = commit.compute_id();
+        commit
+    }
+
+    /// The same commit with `layout` as its layout change, and the id to match.
+    pub fn with_layout(mut self, layout: Option<LayoutDelta>) -> Self {
+        self.layout = layout;
+        self.id = self.compute_id();
+        self
+    }
+
+    /// The same commit with `files` as its changes, and the id to match.
+    pub fn with_files(mut self, files: BTreeMap<String, FileChange>) -> Self {
+        self.files = Some(files);
+        self.id = self.compute_id();
+        self
+    }
+
+    /// The same commit with the files its changes remove recorded as they are
+    /// in `parent`, the tree of its first parent, and the id to match.
+    pub fn with_removed_files(mut self, parent: &Tree) -> Self {
+        let removed = self
+            .changes()
+            .into_iter()
+            .filter(|(_, change)| *change == FileChange::Removed)
+            .filter_map(|(path, _)| Some((path.clone(), parent.get(&path)?.clone())))
+            .collect();
+        self.removed_files = Some(removed).filter(|removed: &Tree| !removed.is_empty());
+        self.id = self.compute_id();
+        self
+    }
+
+    /// What the commit does to each file. Commits from before directories
+    /// were tracked modify `LEGACY_FILE`, creating it if need be.
+    pub fn changes(&self) -> BTreeMap<String, FileChange> {
+        match &self.files {
+            Some(files) => files.clone(),
+            None => BTreeMap::from([(
+                LEGACY_FILE.to_string(),
+                FileChange::Modified(FileEdit {
+                    instructions: self.instructions.clone(),
+                    layout: self.layout.clone(),
+                }),
+            )]),
+        }
+    }
+
+    /// Hashes the canonical serialization (compact JSON, fields in declaration
+    /// order) of everything but the id.
+    pub fn compute_id(&self) -> String {
+        let content = CommitContent {
+            parents: &self.parents,
+            instructions: &self.instructions,
+            layout: &self.layout,
+            files: &self.files,
+            removed_files: &self.removed_files,
+            metadata: &self.metadata,
+        };
+        let encoded = serde_json::to_vec(&content).expect("commit content serializes");
+        Sha256::digest(&encoded)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn first_parent(&self) -> Option<&str> {
+        self.parents.first().map(String::as_str)
+    }
+}
+
+/// The first `SHORT_ID_LEN` characters of `id`.
+pub fn short_id(id: &str) -> &str {
+    &id[..id.len().min(SHORT_ID_LEN)]
+}
+
+/// An instruction stored in a commit failed to apply during reconstruction.
+#[derive(Debug)]
+pub struct ReplayError {
+    pub commit_id: String,
+    pub path: String,
+    /// Zero-based position of the instruction within the commit.
+    pub instruction_index: usize,
+    pub source: ApplyError,
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Commit {} is corrupt: instruction {} for '{}' failed: {}",
+            short_id(&self.commit_id),
+            self.instruction_index + 1,
+            self.path,
+            self.source
+        )
+    }
+}
+
+impl Error for ReplayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
 }
 
 pub fn save_commit(commit: &Commit, dir: &Path) -> Result<(), Box<dyn Error>> {
@@ -20,45 +330,903 @@ pub fn save_commit(commit: &Commit, dir: &Path) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub fn load_commit(id: u64, dir: &Path) -> Result<Commit, Box<dyn Error>> {
+/// Loads commit `id`, checking that its contents still hash to `id`.
+pub fn load_commit(id: &str, dir: &Path) -> Result<Commit, Box<dyn Error>> {
     let path = dir.join(format!("{}.json", id));
     let data = std::fs::read_to_string(path)?;
     let commit: Commit = serde_json::from_str(&data)?;
+    let actual = commit.compute_id();
+    if commit.id != id || actual != id {
+        return Err(format!("Commit {} is corrupt: its contents hash to {}.", id, actual).into());
+    }
     Ok(commit)
 }
 
-pub fn get_current_commit_id(dir: &Path) -> Result<u64, Box<dyn Error>> {
-    let head_path = dir.join("HEAD");
-    let id_str = std::fs::read_to_string(head_path)?;
-    let id = id_str.trim().parse::<u64>()?;
-    Ok(id)
+/// Expands an abbreviated commit id to the single stored commit it prefixes.
+pub fn resolve_commit_id(prefix: &str, dir: &Path) -> Result<String, Box<dyn Error>> {
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a commit id.", prefix).into());
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    let mut matches = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str())
+            && stem.starts_with(&prefix)
+        {
+            matches.push(stem.to_string());
+        }
+    }
+    matches.sort();
+    match matches.len() {
+        0 => Err(format!("Commit '{}' not found.", prefix).into()),
+        1 => Ok(matches.remove(0)),
+        _ => Err(format!(
+            "Commit id '{}' is ambiguous; it matches {}.",
+            prefix,
+            matches
+                .iter()
+                .map(|id| short_id(id))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .into()),
+    }
+}
+
+/// Resolves HEAD to a commit id, following the current branch if there is one.
+pub fn get_current_commit_id(dir: &Path) -> Result<String, Box<dyn Error>> {
+    match read_head(dir)? {
+        Head::Branch(name) => read_branch(&name, dir),
+        Head::Detached(id) => Ok(id),
+    }
 }
 
-pub fn set_current_commit_id(id: u64, dir: &Path) -> Result<(), Box<dyn Error>> {
-    let head_path = dir.join("HEAD");
-    std::fs::write(head_path, id.to_string())?;
-    Ok(())
+/// Points HEAD at `id`: advances the current branch, or moves a detached HEAD.
+pub fn set_current_commit_id(id: &str, dir: &Path) -> Result<(), Box<dyn Error>> {
+    match read_head(dir)? {
+        Head::Branch(name) => write_branch(&name, id, dir),
+        Head::Detached(_) => write_head(&Head::Detached(id.to_string()), dir),
+    }
 }
 
-pub fn get_commit_chain(current_id: u64, dir: &Path) -> Result<Vec<Commit>, Box<dyn Error>> {
+/// The first-parent chain from `current_id` back to the root, newest first.
+pub fn get_commit_chain(current_id: &str, dir: &Path) -> Result<Vec<Commit>, Box<dyn Error>> {
     let mut chain = Vec::new();
-    let mut id = Some(current_id);
+    let mut id = Some(current_id.to_string());
     while let Some(current_id) = id {
-        let commit = load_commit(current_id, dir)?;
-        id = commit.parent_id;
+        let commit = load_commit(&current_id, dir)?;
+        id = commit.first_parent().map(str::to_string);
         chain.push(commit);
     }
     Ok(chain)
 }
 
-pub fn reconstruct_ast(up_to_id: u64, dir: &Path) -> Result<Ast, Box<dyn Error>> {
-    let mut ast = Ast::List(vec![]); // Initial empty list
-    let chain = get_commit_chain(up_to_id, dir)?;
-    for commit in chain.iter().rev() {
-        // From root to up_to_id
-        for instruction in &commit.instructions {
-            ast = apply_instruction(ast, instruction.clone());
+/// Commits replayed since the nearest snapshot after which a new snapshot is due.
+pub const SNAPSHOT_COMMIT_INTERVAL: usize = 32;
+/// Instructions replayed since the nearest snapshot after which a new snapshot is due.
+pub const SNAPSHOT_INSTRUCTION_INTERVAL: usize = 2048;
+
+/// A commit's full tree. Snapshots written before directories were tracked
+/// hold `LEGACY_FILE` alone, and older ones just its AST.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Snapshot {
+    Files { files: Tree },
+    Single(FileState),
+    Bare(Ast),
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    files: &'a Tree,
+    /// Written with `trefoil snapshot`, so kept by `prune_snapshots`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    manual: bool,
+}
+
+/// Whether a snapshot was written by hand; only read by `prune_snapshots`.
+#[derive(Deserialize)]
+struct SnapshotMark {
+    #[serde(default)]
+    manual: bool,
+}
+
+pub fn save_snapshot(id: &str, tree: &Tree, dir: &Path) -> Result<(), Box<dyn Error>> {
+    write_snapshot(id, tree, false, dir)
+}
+
+/// Like `save_snapshot`, for a snapshot the user asked for, which
+/// `prune_snapshots` leaves alone.
+pub fn save_manual_snapshot(id: &str, tree: &Tree, dir: &Path) -> Result<(), Box<dyn Error>> {
+    write_snapshot(id, tree, true, dir)
+}
+
+fn write_snapshot(id: &str, tree: &Tree, manual: bool, dir: &Path) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+    let encoded = serde_json::to_string(&SnapshotRef {
+        files: tree,
+        manual,
+    })?;
+    std::fs::write(dir.join(format!("{}.json", id)), encoded)?;
+    Ok(())
+}
+
+fn is_manual_snapshot(id: &str, dir: &Path) -> Result<bool, Box<dyn Error>> {
+    let data = std::fs::read_to_string(dir.join(format!("{}.json", id)))?;
+    // Older snapshots, including bare ASTs, were never marked.
+    Ok(serde_json::from_str::<SnapshotMark>(&data).is_ok_and(|mark| mark.manual))
+}
+
+/// Loads the tree saved for commit `id`. Old snapshots get the empty layout,
+/// which is what replaying the commits of their time gives too.
+pub fn load_snapshot(id: &str, dir: &Path) -> Result<Option<Tree>, Box<dyn Error>> {
+    let path = dir.join(format!("{}.json", id));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read_to_string(path)?;
+    let single = |file| Tree::from([(LEGACY_FILE.to_string(), file)]);
+    Ok(Some(match serde_json::from_str(&data)? {
+        Snapshot::Files { files } => files,
+        Snapshot::Single(file) => single(file),
+        Snapshot::Bare(ast) => single(FileState {
+            ast,
+            layout: Layout::default(),
+        }),
+    }))
+}
+
+pub fn has_snapshot(id: &str, dir: &Path) -> bool {
+    dir.join(format!("{}.json", id)).exists()
+}
+
+pub fn list_snapshots(dir: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut ids = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .collect::<Vec<String>>();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// File in the snapshots directory that says which snapshot lies nearest
+/// below each commit along first parents.
+const SNAPSHOT_INDEX: &str = "descendants.index";
+
+/// The commits fewer than `SNAPSHOT_COMMIT_INTERVAL` first-parent steps
+/// above a snapshot, so that `reconstruct` can find a descendant's snapshot
+/// without walking back from every one.
+#[derive(Serialize, Deserialize, Default)]
+struct SnapshotIndex {
+    /// The snapshots the index was built from.
+    snapshots: BTreeSet<String>,
+    /// For each commit, the nearest snapshot below it and how many commits
+    /// lie in between.
+    descendants: BTreeMap<String, (String, usize)>,
+}
+
+/// Loads the snapshot index and brings it up to date: new snapshots are
+/// walked back from once, and the index is rebuilt if any it covers is gone.
+fn snapshot_index(
+    commits_dir: &Path,
+    snapshots_dir: &Path,
+) -> Result<SnapshotIndex, Box<dyn Error>> {
+    let snapshots: BTreeSet<String> = list_snapshots(snapshots_dir)?.into_iter().collect();
+    let path = snapshots_dir.join(SNAPSHOT_INDEX);
+    let mut index: SnapshotIndex = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default();
+    if index.snapshots == snapshots {
+        return Ok(index);
+    }
+    if !index.snapshots.is_subset(&snapshots) {
+        index = SnapshotIndex::default();
+    }
+    for id in snapshots.difference(&index.snapshots) {
+        let mut current = id.clone();
+        for distance in 1..SNAPSHOT_COMMIT_INTERVAL {
+            // Snapshots of commits that are gone lead nowhere.
+            let Ok(commit) = load_commit(&current, commits_dir) else {
+                break;
+            };
+            let Some(parent) = commit.first_parent() else {
+                break;
+            };
+            current = parent.to_string();
+            let nearest = index
+                .descendants
+                .entry(current.clone())
+                .or_insert_with(|| (id.clone(), distance));
+            if distance < nearest.1 {
+                *nearest = (id.clone(), distance);
+            }
+        }
+    }
+    index.snapshots = snapshots;
+    // Without write access the walk is just repeated next time.
+    let _ = std::fs::write(path, serde_json::to_string(&index)?);
+    Ok(index)
+}
+
+/// How much work reconstructing a commit takes from its nearest snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayCost {
+    pub commits: usize,
+    pub instructions: usize,
+}
+
+impl ReplayCost {
+    pub fn needs_snapshot(&self) -> bool {
+        self.commits >= SNAPSHOT_COMMIT_INTERVAL
+            || self.instructions >= SNAPSHOT_INSTRUCTION_INTERVAL
+    }
+
+    fn add(&mut self, commit: &Commit) {
+        self.commits += 1;
+        self.instructions += commit
+            .changes()
+            .values()
+            .filter_map(FileChange::edit)
+            .map(|edit| edit.instructions.len())
+            .sum::<usize>();
+    }
+}
+
+pub fn replay_cost(
+    id: &str,
+    commits_dir: &Path,
+    snapshots_dir: &Path,
+) -> Result<ReplayCost, Box<dyn Error>> {
+    let (_, chain) = replay_chain(id, commits_dir, snapshots_dir)?;
+    let mut cost = ReplayCost::default();
+    chain.iter().for_each(|commit| cost.add(commit));
+    Ok(cost)
+}
+
+/// Walks back from `id` to the nearest commit with a snapshot. Returns that
+/// commit's id, if any, and the commits to replay on top of it, newest first.
+fn replay_chain(
+    id: &str,
+    commits_dir: &Path,
+    snapshots_dir: &Path,
+) -> Result<(Option<String>, Vec<Commit>), Box<dyn Error>> {
+    let mut chain = Vec::new();
+    let mut next = Some(id.to_string());
+    while let Some(current_id) = next {
+        if has_snapshot(&current_id, snapshots_dir) {
+            return Ok((Some(current_id), chain));
+        }
+        let commit = load_commit(&current_id, commits_dir)?;
+        next = commit.first_parent().map(str::to_string);
+        chain.push(commit);
+    }
+    Ok((None, chain))
+}
+
+/// Removes snapshots whose commit no longer exists and snapshots `commit`
+/// wrote that are not yet due relative to the nearest snapshot among their
+/// ancestors. Snapshots written with `trefoil snapshot` are kept, and so are
+/// those with an ancestor that fails to load, which may be all that is left
+/// to rebuild that history from. Returns the ids of the removed snapshots.
+pub fn prune_snapshots(
+    commits_dir: &Path,
+    snapshots_dir: &Path,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut by_depth = Vec::new();
+    let mut removed = Vec::new();
+    for id in list_snapshots(snapshots_dir)? {
+        if !commits_dir.join(format!("{}.json", id)).exists() {
+            removed.push(id);
+        } else if !is_manual_snapshot(&id, snapshots_dir)?
+            && let Ok(chain) = get_commit_chain(&id, commits_dir)
+        {
+            by_depth.push((chain.len(), chain));
+        }
+    }
+    // Ancestors first, so each decision sees the snapshots that survive above it.
+    by_depth.sort_by_key(|(depth, _)| *depth);
+    for (_, chain) in by_depth {
+        let commit = &chain[0];
+        let mut cost = match commit.first_parent() {
+            Some(parent_id) => replay_cost(parent_id, commits_dir, snapshots_dir)?,
+            None => ReplayCost::default(),
+        };
+        cost.add(commit);
+        if !cost.needs_snapshot() {
+            std::fs::remove_file(snapshots_dir.join(format!("{}.json", commit.id)))?;
+            removed.push(commit.id.clone());
+        }
+    }
+    for id in &removed {
+        let path = snapshots_dir.join(format!("{}.json", id));
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    removed.sort_unstable();
+    Ok(removed)
+}
+
+/// Rebuilds the files at `up_to_id`, starting from the nearest snapshot
+/// among its ancestors (or the empty root) and replaying the commits after
+/// it, or undoing the commits before it from a nearer snapshot of a
+/// descendant. Commits that predate layouts leave them unchanged, so their trees
+/// usually do not fit them.
+pub fn reconstruct(
+    up_to_id: &str,
+    commits_dir: &Path,
+    snapshots_dir: &Path,
+) -> Result<Tree, Box<dyn Error>> {
+    let (snapshot_id, chain) = replay_chain(up_to_id, commits_dir, snapshots_dir)?;
+    let backwards = if chain.is_empty() {
+        None
+    } else {
+        reconstruct_backwards(up_to_id, chain.len(), commits_dir, snapshots_dir)?
+    };
+    let mut tree = match backwards {
+        Some(tree) => tree,
+        None => {
+            let mut tree = match snapshot_id {
+                Some(id) => load_snapshot(&id, snapshots_dir)?.ok_or_else(|| {
+                    format!(
+                        "Snapshot for commit {} disappeared during reconstruction.",
+                        short_id(&id)
+                    )
+                })?,
+                None => Tree::new(),
+            };
+            for commit in chain.iter().rev() {
+                // From the snapshot to up_to_id
+                apply_changes(&mut tree, commit)?;
+            }
+            tree
+        }
+    };
+    // Commits made before atoms were classified store numbers and the like as symbols.
+    tree.values_mut()
+        .for_each(|file| reclassify_atoms(&mut file.ast));
+    Ok(tree)
+}
+
+/// Rebuilds the files at `id` from the snapshot of a descendant fewer than
+/// `limit` commits after it along first parents, by undoing the commits in
+/// between. `None` if the snapshot index knows no such snapshot or a commit
+/// cannot be undone.
+fn reconstruct_backwards(
+    id: &str,
+    limit: usize,
+    commits_dir: &Path,
+    snapshots_dir: &Path,
+) -> Result<Option<Tree>, Box<dyn Error>> {
+    let index = snapshot_index(commits_dir, snapshots_dir)?;
+    let Some((snapshot_id, distance)) = index.descendants.get(id) else {
+        return Ok(None);
+    };
+    if *distance >= limit {
+        return Ok(None);
+    }
+    let Some(mut tree) = load_snapshot(snapshot_id, snapshots_dir)? else {
+        return Ok(None);
+    };
+    let mut current = snapshot_id.clone();
+    for _ in 0..*distance {
+        let Ok(commit) = load_commit(&current, commits_dir) else {
+            return Ok(None);
+        };
+        let (Some(parent), Some(parent_id)) = (undo_changes(&tree, &commit), commit.first_parent())
+        else {
+            return Ok(None);
+        };
+        tree = parent;
+        current = parent_id.to_string();
+    }
+    Ok(Some(tree))
+}
+
+/// The tree of `commit`'s first parent, found by undoing its changes to
+/// `tree`, the commit's own tree. `None` if the commit does not record what
+/// it removes or overwrites, as commits from before it did not.
+pub fn undo_changes(tree: &Tree, commit: &Commit) -> Option<Tree> {
+    let mut parent = tree.clone();
+    let mut restored = Vec::new();
+    for (path, change) in commit.changes() {
+        match change {
+            FileChange::Added(_) => {
+                parent.remove(&path)?;
+            }
+            FileChange::Modified(edit) => {
+                let file = parent.remove(&path)?;
+                restored.push((path, undo_edit(file, &edit)?));
+            }
+            FileChange::Renamed { from, edit } => {
+                let file = parent.remove(&path)?;
+                restored.push((from, undo_edit(file, &edit)?));
+            }
+            FileChange::Removed => {
+                let file = commit.removed_files.as_ref()?.get(&path)?;
+                restored.push((path, file.clone()));
+            }
+        }
+    }
+    parent.extend(restored);
+    Some(parent)
+}
+
+fn undo_edit(mut file: FileState, edit: &FileEdit) -> Option<FileState> {
+    apply_all(&mut file.ast, &invert_all(&edit.instructions)?).ok()?;
+    if let Some(delta) = &edit.layout {
+        file.layout = delta.invert()?.apply(&file.layout)?;
+    }
+    Some(file)
+}
+
+/// Applies `commit`'s changes to the tree of its first parent. Files are
+/// renamed and removed before any are added or edited, so a commit may move
+/// a file onto a path it frees.
+fn apply_changes(tree: &mut Tree, commit: &Commit) -> Result<(), Box<dyn Error>> {
+    let corrupt =
+        |problem: String| format!("Commit {} is corrupt: {}", short_id(&commit.id), problem);
+    let changes = commit.changes();
+    let mut moved = HashMap::new();
+    for (path, change) in &changes {
+        match change {
+            FileChange::Renamed { from, .. } => {
+                let file = tree.remove(from).ok_or_else(|| {
+                    corrupt(format!("it renames '{}', which does not exist.", from))
+                })?;
+                moved.insert(from.clone(), file);
+            }
+            FileChange::Removed => {
+                tree.remove(path).ok_or_else(|| {
+                    corrupt(format!("it removes '{}', which does not exist.", path))
+                })?;
+            }
+            FileChange::Added(_) | FileChange::Modified(_) => {}
+        }
+    }
+    for (path, change) in changes {
+        let (mut file, edit) = match change {
+            FileChange::Added(edit) => (FileState::default(), edit),
+            FileChange::Modified(edit) => (tree.remove(&path).unwrap_or_default(), edit),
+            FileChange::Renamed { from, edit } => (
+                moved
+                    .remove(&from)
+                    .ok_or_else(|| corrupt(format!("it renames '{}' more than once.", from)))?,
+                edit,
+            ),
+            FileChange::Removed => continue,
+        };
+        apply_all(&mut file.ast, &edit.instructions).map_err(|(instruction_index, source)| {
+            ReplayError {
+                commit_id: commit.id.clone(),
+                path: path.clone(),
+                instruction_index,
+                source,
+            }
+        })?;
+        if let Some(delta) = &edit.layout {
+            file.layout = delta.apply(&file.layout).ok_or_else(|| {
+                corrupt(format!(
+                    "its layout changes to '{}' do not fit the parent's layout.",
+                    path
+                ))
+            })?;
+        }
+        tree.insert(path, file);
+    }
+    Ok(())
+}
+
+/// Above this similarity a removed file and an added one are taken to be
+/// the same file, renamed.
+const RENAME_SIMILARITY: f64 = 0.5;
+
+/// The changes turning the files of `old` into those of `new`. An added file
+/// that matches a removed one closely enough is recorded as a rename.
+pub fn diff_trees(
+    old: &Tree,
+    new: &Tree,
+    algorithm: DiffAlgorithm,
+) -> BTreeMap<String, FileChange> {
+    let mut changes = BTreeMap::new();
+    let mut removed: Vec<&String> = old.keys().filter(|path| !new.contains_key(*path)).collect();
+    for (path, file) in new {
+        let change = match old.get(path) {
+            Some(old_file) => FileChange::Modified(FileEdit::between(old_file, file, algorithm)),
+            None => {
+                let renamed = removed
+                    .iter()
+                    .enumerate()
+                    .map(|(i, from)| {
+                        let score = if old[*from].ast == file.ast {
+                            1.0
+                        } else {
+                            similarity(&old[*from].ast, &file.ast)
+                        };
+                        (i, score)
+                    })
+                    .filter(|&(_, score)| score >= RENAME_SIMILARITY)
+                    .max_by(|a, b| a.1.total_cmp(&b.1));
+                match renamed {
+                    Some((i, _)) => {
+                        let from = removed.remove(i);
+                        FileChange::Renamed {
+                            from: from.clone(),
+                            edit: FileEdit::between(&old[from], file, algorithm),
+                        }
+                    }
+                    None => {
+                        FileChange::Added(FileEdit::between(&FileState::default(), file, algorithm))
+                    }
+                }
+            }
+        };
+        if change.edit().is_some_and(FileEdit::is_empty)
+            && matches!(change, FileChange::Modified(_))
+        {
+            continue;
+        }
+        changes.insert(path.clone(), change);
+    }
+    for path in removed {
+        changes.insert(path.clone(), FileChange::Removed);
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use crate::workdir::parse_file;
+
+    #[test]
+    fn test_commit_id_covers_contents() {
+        let root = Commit::new(vec![], vec![], CommitMetadata::default());
+        assert_eq!(root.id.len(), 64);
+        assert_eq!(
+            root.id,
+            Commit::new(vec![], vec![], CommitMetadata::default()).id
+        );
+
+        let child = Commit::new(vec![root.id.clone()], vec![], CommitMetadata::default());
+        assert_ne!(child.id, root.id);
+        let edited = Commit::new(
+            vec![root.id.clone()],
+            vec![Instruction::Delete {
+                path: vec![],
+                index: 0,
+                node: None,
+            }],
+            CommitMetadata::default(),
+        );
+        assert_ne!(edited.id, child.id);
+        let described = Commit::new(
+            vec![root.id.clone()],
+            vec![],
+            CommitMetadata {
+                message: "Describe".to_string(),
+                ..CommitMetadata::default()
+            },
+        );
+        assert_ne!(described.id, child.id);
+        let reformatted = Commit::new(vec![root.id.clone()], vec![], CommitMetadata::default())
+            .with_layout(Some(LayoutDelta {
+                trailing: Some("\n".to_string()),
+                ..LayoutDelta::default()
+            }));
+        assert_ne!(reformatted.id, child.id);
+        assert_eq!(reformatted.compute_id(), reformatted.id);
+    }
+
+    #[test]
+    fn test_old_snapshots_load_as_trees() {
+        let temp = TempDir::new("snapshots");
+        let dir = temp.path();
+        // Written before snapshots carried layouts, and before they carried directories.
+        std::fs::write(
+            dir.join("bare.json"),
+            r#"{"List":[{"List":[{"Atom":"x"}]}]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("single.json"),
+            r#"{"ast":{"List":[{"List":[{"Atom":"x"}]}]},"layout":{"tokens":[{},{},{}],"trailing":"\n"}}"#,
+        )
+        .unwrap();
+        let ast = Ast::list(vec![Ast::list(vec![Ast::Atom("x".to_string())])]);
+        let bare = load_snapshot("bare", dir).unwrap().unwrap();
+        assert_eq!(bare.keys().collect::<Vec<_>>(), vec![LEGACY_FILE]);
+        assert_eq!(bare[LEGACY_FILE].ast, ast);
+        assert_eq!(bare[LEGACY_FILE].layout, Layout::default());
+        let single = load_snapshot("single", dir).unwrap().unwrap();
+        assert_eq!(single[LEGACY_FILE].layout.render(&ast).unwrap(), "(x)\n");
+
+        let mut tree = single.clone();
+        tree.insert("lib/util.lisp".to_string(), FileState::default());
+        save_snapshot("new", &tree, dir).unwrap();
+        assert_eq!(load_snapshot("new", dir).unwrap(), Some(tree));
+    }
+
+    #[test]
+    fn test_diff_trees_roundtrips_through_a_commit() {
+        let file = |source: &str| parse_file(source).0;
+        let old = Tree::from([
+            ("a.lisp".to_string(), file("(define (f x) (* x x))\n")),
+            ("b.lisp".to_string(), file("(f 1)\n")),
+            ("c.lisp".to_string(), file("(g 2)")),
+            ("e.lisp".to_string(), file("; gone\n(zzz 9 8)\n")),
+        ]);
+        let new = Tree::from([
+            ("b.lisp".to_string(), file("(f  1)\n")),
+            ("c.lisp".to_string(), file("(g 3)")),
+            ("lib/a.lisp".to_string(), file("(define (f x) (* x x x))\n")),
+            ("d.lisp".to_string(), file("(h)")),
+        ]);
+        let changes = diff_trees(&old, &new, DiffAlgorithm::Fast);
+        let kinds: Vec<String> = changes
+            .iter()
+            .map(|(path, change)| change.summary(path))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "modified b.lisp",
+                "modified c.lisp",
+                "added d.lisp",
+                "removed e.lisp",
+                "renamed a.lisp -> lib/a.lisp"
+            ]
+        );
+        assert!(changes["b.lisp"].edit().unwrap().instructions.is_empty());
+
+        let commit = Commit::new(vec![], vec![], CommitMetadata::default()).with_files(changes);
+        let mut tree = old.clone();
+        apply_changes(&mut tree, &commit).unwrap();
+        assert_eq!(tree, new);
+        assert!(diff_trees(&new, &new, DiffAlgorithm::Fast).is_empty());
+
+        // Undoing needs the removed file.
+        assert_eq!(undo_changes(&new, &commit), None);
+        let commit = commit.with_removed_files(&old);
+        assert_eq!(undo_changes(&new, &commit), Some(old));
+    }
+
+    /// Saves a line of commits from the root, the nth of which inserts
+    /// `instructions[n]` atoms, and returns their ids.
+    fn save_line(instructions: &[usize], commits_dir: &Path) -> Vec<String> {
+        std::fs::create_dir_all(commits_dir).unwrap();
+        let mut ids: Vec<String> = Vec::new();
+        for &count in instructions {
+            let edit = FileEdit {
+                instructions: vec![
+                    Instruction::Insert {
+                        path: vec![],
+                        index: 0,
+                        node: Ast::Atom("x".to_string()),
+                    };
+                    count
+                ],
+                layout: None,
+            };
+            let files = BTreeMap::from([("a.lisp".to_string(), FileChange::Modified(edit))]);
+            let parents = ids.last().cloned().into_iter().collect();
+            let commit = Commit::new(parents, vec![], CommitMetadata::default())
+                .with_files(files.into_iter().filter(|_| count > 0).collect());
+            save_commit(&commit, commits_dir).unwrap();
+            ids.push(commit.id);
         }
+        ids
+    }
+
+    #[test]
+    fn test_snapshots_fall_due_at_the_intervals() {
+        let dir = TempDir::new("intervals");
+        let (commits_dir, snapshots_dir) =
+            (dir.path().join("commits"), dir.path().join("snapshots"));
+        let due = |id: &str| {
+            replay_cost(id, &commits_dir, &snapshots_dir)
+                .unwrap()
+                .needs_snapshot()
+        };
+        let ids = save_line(&[0; SNAPSHOT_COMMIT_INTERVAL], &commits_dir);
+        assert!(!due(&ids[SNAPSHOT_COMMIT_INTERVAL - 2]));
+        assert!(due(&ids[SNAPSHOT_COMMIT_INTERVAL - 1]));
+
+        let ids = save_line(&[0, SNAPSHOT_INSTRUCTION_INTERVAL - 1, 1], &commits_dir);
+        assert_eq!(
+            replay_cost(&ids[1], &commits_dir, &snapshots_dir).unwrap(),
+            ReplayCost {
+                commits: 2,
+                instructions: SNAPSHOT_INSTRUCTION_INTERVAL - 1
+            }
+        );
+        assert!(!due(&ids[1]));
+        assert!(due(&ids[2]));
+
+        // Costs count from the nearest snapshot.
+        save_snapshot(&ids[1], &Tree::new(), &snapshots_dir).unwrap();
+        assert!(!due(&ids[2]));
+    }
+
+    #[test]
+    fn test_prune_keeps_due_snapshots() {
+        let dir = TempDir::new("prune");
+        let (commits_dir, snapshots_dir) =
+            (dir.path().join("commits"), dir.path().join("snapshots"));
+        let ids = save_line(&[0; SNAPSHOT_COMMIT_INTERVAL + 8], &commits_dir);
+        let due = &ids[SNAPSHOT_COMMIT_INTERVAL - 1];
+        let early = &ids[SNAPSHOT_COMMIT_INTERVAL + 3];
+        let manual = &ids[SNAPSHOT_COMMIT_INTERVAL + 5];
+        let orphan = "0".repeat(64);
+        for id in [due, early, &orphan] {
+            save_snapshot(id, &Tree::new(), &snapshots_dir).unwrap();
+        }
+        save_manual_snapshot(manual, &Tree::new(), &snapshots_dir).unwrap();
+        // A snapshot whose history is broken further up stays.
+        let broken = save_line(&[1; 3], &commits_dir);
+        std::fs::remove_file(commits_dir.join(format!("{}.json", broken[0]))).unwrap();
+        save_snapshot(&broken[2], &Tree::new(), &snapshots_dir).unwrap();
+
+        let mut removed = vec![early.clone(), orphan];
+        removed.sort();
+        assert_eq!(
+            prune_snapshots(&commits_dir, &snapshots_dir).unwrap(),
+            removed
+        );
+        let mut kept = vec![due.clone(), manual.clone(), broken[2].clone()];
+        kept.sort();
+        assert_eq!(list_snapshots(&snapshots_dir).unwrap(), kept);
+        assert!(
+            prune_snapshots(&commits_dir, &snapshots_dir)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_backwards_from_a_later_snapshot() {
+        let dir = TempDir::new("backwards");
+        let (commits_dir, snapshots_dir) =
+            (dir.path().join("commits"), dir.path().join("snapshots"));
+        std::fs::create_dir_all(&commits_dir).unwrap();
+        let tree = |source: &str| Tree::from([("a.lisp".to_string(), parse_file(source).0)]);
+        let trees = [
+            Tree::new(),
+            tree("(a 1)"),
+            tree("(a 2)\n(b)"),
+            tree("(b 3)"),
+        ];
+        let mut ids: Vec<String> = Vec::new();
+        for (i, files) in trees.iter().enumerate() {
+            let parents = ids.last().cloned().into_iter().collect();
+            let parent = if i == 0 {
+                Tree::new()
+            } else {
+                trees[i - 1].clone()
+            };
+            let commit = Commit::new(parents, vec![], CommitMetadata::default())
+                .with_files(diff_trees(&parent, files, DiffAlgorithm::Fast))
+                .with_removed_files(&parent);
+            save_commit(&commit, &commits_dir).unwrap();
+            ids.push(commit.id);
+        }
+        save_snapshot(&ids[3], &trees[3], &snapshots_dir).unwrap();
+
+        assert_eq!(
+            reconstruct_backwards(&ids[1], 3, &commits_dir, &snapshots_dir).unwrap(),
+            Some(trees[1].clone())
+        );
+        // Two commits back is no nearer than replaying two from the root.
+        assert_eq!(
+            reconstruct_backwards(&ids[1], 2, &commits_dir, &snapshots_dir).unwrap(),
+            None
+        );
+        assert_eq!(
+            reconstruct(&ids[2], &commits_dir, &snapshots_dir).unwrap(),
+            trees[2]
+        );
+        assert!(snapshots_dir.join(SNAPSHOT_INDEX).is_file());
+        assert_eq!(
+            list_snapshots(&snapshots_dir).unwrap(),
+            vec![ids[3].clone()]
+        );
+
+        // The index follows snapshots that come and go.
+        std::fs::remove_file(snapshots_dir.join(format!("{}.json", ids[3]))).unwrap();
+        assert_eq!(
+            reconstruct_backwards(&ids[1], 3, &commits_dir, &snapshots_dir).unwrap(),
+            None
+        );
+        save_snapshot(&ids[2], &trees[2], &snapshots_dir).unwrap();
+        assert_eq!(
+            reconstruct_backwards(&ids[1], 3, &commits_dir, &snapshots_dir).unwrap(),
+            Some(trees[1].clone())
+        );
+    }
+
+    #[test]
+    fn test_commit_without_files_changes_the_legacy_file() {
+        let commit = Commit::new(vec![], vec![], CommitMetadata::default());
+        let mut tree = Tree::new();
+        apply_changes(&mut tree, &commit).unwrap();
+        assert_eq!(
+            tree,
+            Tree::from([(LEGACY_FILE.to_string(), FileState::default())])
+        );
+
+        let commit = commit.with_files(BTreeMap::new());
+        assert_ne!(
+            commit.id,
+            Commit::new(vec![], vec![], CommitMetadata::default()).id
+        );
+        let mut tree = Tree::new();
+        apply_changes(&mut tree, &commit).unwrap();
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_commit_roundtrips_parents() {
+        let commit = Commit::new(
+            vec!["ab".to_string(), "cd".to_string()],
+            vec![],
+            CommitMetadata::default(),
+        );
+        let json = serde_json::to_string(&commit).unwrap();
+        assert!(json.contains(r#""parents":["ab","cd"]"#));
+        let loaded: Commit = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.parents, commit.parents);
+        assert_eq!(loaded.compute_id(), commit.id);
+    }
+
+    #[test]
+    fn test_commit_without_metadata_keeps_its_id() {
+        // Written before commits carried authors, messages or time zones.
+        let json = r#"{"id":"","parents":[],"instructions":[],"timestamp":0}"#;
+        let commit: Commit = serde_json::from_str(json).unwrap();
+        assert_eq!(commit.metadata, CommitMetadata::default());
+        assert_eq!(
+            serde_json::to_string(&commit).unwrap(),
+            json,
+            "default metadata must serialize as before"
+        );
+        assert_eq!(
+            commit.compute_id(),
+            "26721b94c28211155d1ac53ed491967755877ddef26843c5ce7f2f1a264bb7fd"
+        );
+
+        let signed = Commit::new(
+            vec![],
+            vec![],
+            CommitMetadata {
+                timestamp: 1_700_000_000,
+                tz_offset: 120,
+                author: Some(Signature {
+                    name: "Ada".to_string(),
+                    email: "ada@example.com".to_string(),
+                }),
+                committer: None,
+                message: "Start".to_string(),
+            },
+        );
+        let json = serde_json::to_string(&signed).unwrap();
+        let loaded: Commit = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.metadata, signed.metadata);
+        assert_eq!(loaded.compute_id(), signed.id);
+        assert_eq!(
+            signed.metadata.date().as_deref(),
+            Some("Wed Nov 15 00:13:20 2023 +0200")
+        );
+        assert_eq!(commit.metadata.date(), None);
     }
-    Ok(ast)
 }
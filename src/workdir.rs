@@ -0,0 +1,441 @@
+use crate::diff::{DiffAlgorithm, diff};
+use crate::layout::Layout;
+use crate::parser::{ParseError, parse_recovering, tokenize};
+use crate::pretty::{PrettyConfig, pretty};
+use crate::vc::{FileState, Tree};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Patterns of paths to leave untracked, read from the repository root.
+pub const IGNORE_FILE: &str = ".trefoilignore";
+
+/// Extensions of the files trefoil tracks.
+pub const SOURCE_EXTENSIONS: &[&str] = &[
+    "cl", "clj", "cljc", "cljs", "edn", "el", "fnl", "lisp", "lsp", "rkt", "scm", "ss",
+];
+
+/// Patterns in the style of `.gitignore`, one per line. `*` and `?` match
+/// within a path segment and `**` across segments. A pattern containing a
+/// `/` other than at its end is matched against the path from the root;
+/// one without is matched against names at any depth. A trailing `/`
+/// matches directories only, a leading `!` brings back a path an earlier
+/// pattern ignored, and `#` starts a comment.
+#[derive(Clone, Debug, Default)]
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Clone, Debug)]
+struct Pattern {
+    glob: Vec<char>,
+    anchored: bool,
+    directory_only: bool,
+    negated: bool,
+}
+
+impl Ignore {
+    pub fn parse(text: &str) -> Self {
+        let patterns = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let (directory_only, line) = match line.strip_suffix('/') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                Pattern {
+                    glob: line.trim_start_matches('/').chars().collect(),
+                    anchored: line.contains('/'),
+                    directory_only,
+                    negated,
+                }
+            })
+            .collect();
+        Ignore { patterns }
+    }
+
+    /// The ignore file at `root`, or no patterns if there is none.
+    pub fn load(root: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = root.join(IGNORE_FILE);
+        if !path.exists() {
+            return Ok(Ignore::default());
+        }
+        Ok(Ignore::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Whether the last pattern matching `path`, relative to the root, ignores it.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let path: Vec<char> = path.chars().collect();
+        let name_start = path
+            .iter()
+            .rposition(|&c| c == '/')
+            .map_or(0, |slash| slash + 1);
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if pattern.directory_only && !is_dir {
+                continue;
+            }
+            let text = if pattern.anchored {
+                &path[..]
+            } else {
+                &path[name_start..]
+            };
+            if glob_match(&pattern.glob, text) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => (0..=text.len())
+            .filter(|&i| i == 0 || text[i - 1] == '/')
+            .any(|i| glob_match(rest, &text[i..])),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(rest, &text[i..])),
+        ['?', rest @ ..] => text.first().is_some_and(|&c| c != '/') && glob_match(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Whether trefoil tracks files named like `path`.
+pub fn is_source_file(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| SOURCE_EXTENSIONS.contains(&extension))
+}
+
+/// The `/`-separated paths, relative to `root`, of the source files under it
+/// that are not ignored, sorted. The repository directory is never listed.
+pub fn list_files(root: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let ignore = Ignore::load(root)?;
+    let mut files = Vec::new();
+    walk(root, "", &ignore, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn walk(
+    root: &Path,
+    dir: &str,
+    ignore: &Ignore,
+    files: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    for entry in std::fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let path = if dir.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", dir, name)
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if name != ".trefoil" && !ignore.is_ignored(&path, true) {
+                walk(root, &path, ignore, files)?;
+            }
+        } else if file_type.is_file() && is_source_file(&path) && !ignore.is_ignored(&path, false) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Parses `source` as far as possible, along with its layout.
+pub fn parse_file(source: &str) -> (FileState, Vec<ParseError>) {
+    let tokens = tokenize(source);
+    let (ast, errors) = parse_recovering(&tokens);
+    let layout = Layout::extract(source, &tokens, &ast).unwrap_or_default();
+    (FileState { ast, layout }, errors)
+}
+
+/// Reads and parses the file at `path` under `root`, along with a rendered
+/// diagnostic for every parse error.
+pub fn read_file(root: &Path, path: &str) -> Result<(FileState, Vec<String>), Box<dyn Error>> {
+    let source = std::fs::read_to_string(root.join(path))
+        .map_err(|err| format!("Cannot read '{}': {}", path, err))?;
+    let (file, errors) = parse_file(&source);
+    let diagnostics = errors.iter().map(|err| err.render(&source, path)).collect();
+    Ok((file, diagnostics))
+}
+
+/// Reads every file `list_files` finds under `root`.
+pub fn read_tree(root: &Path) -> Result<(Tree, Vec<String>), Box<dyn Error>> {
+    let mut tree = Tree::new();
+    let mut diagnostics = Vec::new();
+    for path in list_files(root)? {
+        let (file, file_diagnostics) = read_file(root, &path)?;
+        diagnostics.extend(file_diagnostics);
+        tree.insert(path, file);
+    }
+    Ok((tree, diagnostics))
+}
+
+/// The text of `file` exactly as laid out by its layout if that fits, and
+/// pretty-printed otherwise, with the layout of that text.
+pub fn render_file(file: &FileState, format: &PrettyConfig) -> (String, Layout) {
+    match file.layout.render(&file.ast) {
+        Some(text) => (text, file.layout.clone()),
+        None => {
+            let text = pretty(&file.ast, format);
+            let layout = parse_file(&text).0.layout;
+            (text, layout)
+        }
+    }
+}
+
+/// Replaces the files of `old` under `root` with those of `new`: writes each
+/// file of `new` with `render_file` and removes the ones only `old` has,
+/// along with directories left empty. Returns `new` with the layouts written.
+pub fn write_tree(
+    root: &Path,
+    old: &Tree,
+    new: &Tree,
+    format: &PrettyConfig,
+) -> Result<Tree, Box<dyn Error>> {
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        let file = root.join(path);
+        if file.exists() {
+            std::fs::remove_file(&file)?;
+        }
+        // Stops at the first directory that is not empty, or at the root.
+        for dir in Path::new(path).ancestors().skip(1) {
+            if dir.as_os_str().is_empty() || std::fs::remove_dir(root.join(dir)).is_err() {
+                break;
+            }
+        }
+    }
+    let mut written = Tree::new();
+    for (path, file) in new {
+        let (text, layout) = render_file(file, format);
+        let target = root.join(path);
+        if std::fs::read_to_string(&target).ok().as_deref() != Some(text.as_str()) {
+            if let Some(dir) = target.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&target, text)?;
+        }
+        written.insert(
+            path.clone(),
+            FileState {
+                ast: file.ast.clone(),
+                layout,
+            },
+        );
+    }
+    Ok(written)
+}
+
+/// Whether the working copy of a file differs from its committed state. The
+/// layouts of commits made before layouts were recorded do not fit their
+/// trees, so only the trees are compared for them.
+pub fn is_modified(committed: &FileState, working: &FileState) -> bool {
+    committed.ast != working.ast
+        || (committed.layout.render(&committed.ast).is_some() && committed.layout != working.layout)
+}
+
+/// Whether any file was added, removed or modified between the two trees.
+pub fn has_changes(committed: &Tree, working: &Tree) -> bool {
+    committed.len() != working.len()
+        || committed
+            .iter()
+            .any(|(path, file)| working.get(path).is_none_or(|w| is_modified(file, w)))
+}
+
+/// How a file in the working directory compares to HEAD.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileStatus {
+    Clean,
+    /// Its tree differs by this many instructions.
+    Modified(usize),
+    /// Only its whitespace or spelling differs.
+    Reformatted,
+    /// It has this many parse errors, so it cannot be compared.
+    Unparsable(usize),
+    /// Tracked, but no longer on disk or now ignored.
+    Deleted,
+    /// On disk, but not in HEAD.
+    Untracked,
+}
+
+impl Display for FileStatus {
+    /// The status as one word, as `status --porcelain` prints it.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let word = match self {
+            FileStatus::Clean => "clean",
+            FileStatus::Modified(_) => "modified",
+            FileStatus::Reformatted => "reformatted",
+            FileStatus::Unparsable(_) => "unparsable",
+            FileStatus::Deleted => "deleted",
+            FileStatus::Untracked => "untracked",
+        };
+        write!(f, "{}", word)
+    }
+}
+
+/// The status of every file in `head` and every untracked file under `root`,
+/// sorted by path.
+pub fn status(root: &Path, head: &Tree) -> Result<Vec<(String, FileStatus)>, Box<dyn Error>> {
+    let listed = list_files(root)?;
+    let mut statuses = Vec::new();
+    for (path, committed) in head {
+        if listed.binary_search(path).is_err() {
+            statuses.push((path.clone(), FileStatus::Deleted));
+            continue;
+        }
+        let (working, diagnostics) = read_file(root, path)?;
+        let status = if !diagnostics.is_empty() {
+            FileStatus::Unparsable(diagnostics.len())
+        } else {
+            match diff(&committed.ast, &working.ast, DiffAlgorithm::Fast).len() {
+                0 if is_modified(committed, &working) => FileStatus::Reformatted,
+                0 => FileStatus::Clean,
+                changes => FileStatus::Modified(changes),
+            }
+        };
+        statuses.push((path.clone(), status));
+    }
+    for path in listed {
+        if !head.contains_key(&path) {
+            statuses.push((path, FileStatus::Untracked));
+        }
+    }
+    statuses.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_ignore_patterns() {
+        let ignore = Ignore::parse(
+            "# build output\ntarget/\n*.tmp.lisp\n/scratch.lisp\nvendor/**/test\n!keep.tmp.lisp\n",
+        );
+        assert!(ignore.is_ignored("target", true));
+        assert!(ignore.is_ignored("lib/target", true));
+        assert!(!ignore.is_ignored("target", false));
+        assert!(ignore.is_ignored("a.tmp.lisp", false));
+        assert!(ignore.is_ignored("lib/b.tmp.lisp", false));
+        assert!(!ignore.is_ignored("keep.tmp.lisp", false));
+        assert!(ignore.is_ignored("scratch.lisp", false));
+        assert!(!ignore.is_ignored("lib/scratch.lisp", false));
+        assert!(ignore.is_ignored("vendor/test", true));
+        assert!(ignore.is_ignored("vendor/a/b/test", true));
+        assert!(!ignore.is_ignored("lib/vendor/test", true));
+        assert!(!ignore.is_ignored("core.lisp", false));
+    }
+
+    #[test]
+    fn test_read_and_write_tree() {
+        let temp = TempDir::new("workdir");
+        let root = temp.path();
+        let write = |path: &str, text: &str| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        };
+        write("main.lisp", "(main)  \n");
+        write("lib/util.scm", "(define (id x)\n  x)\n");
+        write("lib/notes.txt", "not code");
+        write("build/out.lisp", "(generated)");
+        write(".trefoil/commits/x.lisp", "(internal)");
+        write(IGNORE_FILE, "build/\n");
+
+        let (tree, diagnostics) = read_tree(root).unwrap();
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            tree.keys().collect::<Vec<_>>(),
+            vec!["lib/util.scm", "main.lisp"]
+        );
+
+        let mut new = tree.clone();
+        new.remove("lib/util.scm");
+        let (moved, _) = parse_file("(a b)");
+        new.insert("src/app/a.lisp".to_string(), moved);
+        let written = write_tree(root, &tree, &new, &PrettyConfig::default()).unwrap();
+        assert_eq!(written, new);
+        assert!(!root.join("lib/util.scm").exists());
+        assert!(root.join("lib/notes.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(root.join("main.lisp")).unwrap(),
+            "(main)  \n"
+        );
+        assert_eq!(read_tree(root).unwrap().0, new);
+        assert!(!has_changes(&new, &read_tree(root).unwrap().0));
+
+        // Without a fitting layout the file is pretty-printed.
+        let bare = Tree::from([(
+            "main.lisp".to_string(),
+            FileState {
+                ast: tree["main.lisp"].ast.clone(),
+                layout: Layout::default(),
+            },
+        )]);
+        let written = write_tree(root, &new, &bare, &PrettyConfig::default()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("main.lisp")).unwrap(),
+            "(main)\n"
+        );
+        assert!(!root.join("src").exists());
+        assert!(!is_modified(&bare["main.lisp"], &written["main.lisp"]));
+    }
+
+    #[test]
+    fn test_status() {
+        let temp = TempDir::new("status");
+        let root = temp.path();
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        let head: Tree = [
+            ("clean.lisp", "(a)\n"),
+            ("edited.lisp", "(a b)\n"),
+            ("spaced.lisp", "(a b)\n"),
+            ("broken.lisp", "(a)\n"),
+            ("gone.lisp", "(a)\n"),
+        ]
+        .into_iter()
+        .map(|(path, text)| (path.to_string(), parse_file(text).0))
+        .collect();
+        for (path, text) in [
+            ("clean.lisp", "(a)\n"),
+            ("edited.lisp", "(a c d)\n"),
+            ("spaced.lisp", "(a  b)\n"),
+            ("broken.lisp", "(a"),
+            ("lib/new.scm", "(n)"),
+        ] {
+            std::fs::write(root.join(path), text).unwrap();
+        }
+        let statuses: Vec<String> = status(root, &head)
+            .unwrap()
+            .iter()
+            .map(|(path, status)| format!("{} {}", status, path))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                "unparsable broken.lisp",
+                "clean clean.lisp",
+                "modified edited.lisp",
+                "deleted gone.lisp",
+                "untracked lib/new.scm",
+                "reformatted spaced.lisp",
+            ]
+        );
+    }
+}